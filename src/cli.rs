@@ -1,6 +1,6 @@
-use std::sync::LazyLock;
+use std::{sync::LazyLock, time::Duration};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use console::Emoji;
 use indicatif::ProgressStyle;
 
@@ -12,6 +12,8 @@ pub static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍 ", "");
 pub static HOURGLASS: Emoji<'_, '_> = Emoji("⌛ ", "");
 pub static CHECKLIST: Emoji<'_, '_> = Emoji("📋 ", "");
 pub static CHECKMARK: Emoji<'_, '_> = Emoji("✅ ", "");
+pub static CROSSMARK: Emoji<'_, '_> = Emoji("❌ ", "");
+pub static WARNING: Emoji<'_, '_> = Emoji("⚠️ ", "");
 
 pub static PROGRESS_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
     ProgressStyle::with_template(
@@ -19,6 +21,52 @@ pub static PROGRESS_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
     ).expect("Could not parse progress template, this indicates a bug in this application")
 });
 
+pub static TRANSFER_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
+    ProgressStyle::with_template(
+    "  {bar:20.green/white} {bytes:>10}/{total_bytes:10} {wide_msg}",
+    ).expect("Could not parse transfer template, this indicates a bug in this application")
+});
+
+// //////////////// //
+// Argument parsers //
+// //////////////// //
+
+/// Parses a size in bytes, optionally followed by a binary unit (e.g. `512M` or `2GiB`).
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()));
+    let number: u64 = number.parse().map_err(|_| format!("{text} is not a valid size"))?;
+
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("{unit} is not a valid unit, use K, M, G or T")),
+    };
+
+    number.checked_mul(multiplier).ok_or_else(|| format!("{text} is too large"))
+}
+
+/// Parses a duration, as a number optionally followed by a unit of `s`, `m`, `h` or `d` (e.g. `90m` or `7d`).
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let (number, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()));
+    let number: u64 = number.parse().map_err(|_| format!("{text} is not a valid duration"))?;
+
+    let multiplier: u64 = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("{unit} is not a valid unit, use s, m, h or d")),
+    };
+
+    number.checked_mul(multiplier).map(Duration::from_secs).ok_or_else(|| format!("{text} is too long"))
+}
+
 // ///////////// //
 // CLI interface //
 // ///////////// //
@@ -41,6 +89,24 @@ pub enum Commands {
 
     /// Update to the latest version of the currently used channel.
     Update(UpdateArgs),
+
+    /// Verify a folder against a given version without changing anything.
+    Verify(VerifyArgs),
+
+    /// Remove files from the local download cache.
+    PruneCache(PruneCacheArgs),
+
+    /// List the versions in the storage.
+    Versions(VersionsArgs),
+
+    /// Remove files from the storage that are not referenced by any version.
+    Gc(GcArgs),
+
+    /// Show the changes between two versions.
+    Diff(DiffArgs),
+
+    /// Point a channel to a version.
+    Promote(PromoteArgs),
 }
 
 #[derive(Args, Debug)]
@@ -60,6 +126,64 @@ pub struct CreateArgs {
     /// The directory to create the update from (defaults to the current folder if omitted).
     #[arg(short, long)]
     pub input_dir: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container or a `file://` URL of a local directory (defaults to the S3 storage configured using the `AWS_*` environment variables if omitted).
+    ///
+    /// Example: file:///mnt/updates
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of files to process at once (defaults to 4 if omitted).
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// The definition schema version to use (defaults to the oldest version that can represent the version: 3 when files are chunked, 2 when using anything older versions of h3xup can't handle, like files larger than 4 GiB, other compression algorithms than Brotli, patches or file permissions, and 1 otherwise). Version 1 can be read by older versions of h3xup. Version 3 is required for chunked files.
+    #[arg(long)]
+    pub definition_version: Option<u32>,
+
+    /// The name(s) of previous versions to create patches from (comma separated). Clients that have one of these versions installed only download the patches of changed files instead of the whole files.
+    #[arg(long, value_delimiter = ',')]
+    pub delta_from: Vec<String>,
+
+    /// The compression algorithm(s) to use (comma separated): `brotli`, `zstd` or `none`. If more than one is given, each file is stored using the one that gives the smallest result; `smallest` tries all of them.
+    #[arg(short, long, value_delimiter = ',', default_value = "brotli")]
+    pub compression: Vec<String>,
+
+    /// Store large files as content-defined chunks, so clients only download the chunks that changed. Requires definition schema version 3.
+    #[arg(long)]
+    pub chunked: bool,
+
+    /// The file containing the Base64 encoded Ed25519 secret key to sign the version definition(s) with (defaults to the key in the `UPDTR_SIGNING_KEY` env var, if set).
+    #[arg(long)]
+    pub signing_key: Option<String>,
+
+    /// The release notes of the version, shown when switching to it.
+    #[arg(long, conflicts_with = "release_notes_file")]
+    pub release_notes: Option<String>,
+
+    /// The file containing the release notes of the version, shown when switching to it.
+    #[arg(long)]
+    pub release_notes_file: Option<String>,
+
+    /// The Git commit the version was built from.
+    #[arg(long)]
+    pub git_commit: Option<String>,
+
+    /// The host the version was built on.
+    #[arg(long)]
+    pub build_host: Option<String>,
+
+    /// The oldest version of h3xup that can switch to the version, e.g. `0.3.0`. Older versions of h3xup that know about this refuse to switch to it.
+    #[arg(long)]
+    pub min_updater_version: Option<String>,
+
+    /// Record the modification times of the files, so switching restores them. Unix permissions are always recorded.
+    #[arg(long)]
+    pub preserve_mtime: bool,
 }
 
 #[derive(Args, Debug)]
@@ -75,11 +199,39 @@ pub struct SwitchArgs {
     #[arg(short, long)]
     pub output_dir: Option<String>,
 
-    /// The endpoint and bucket of the S3 (compatible) storage. Must currently use TLS with valid cert and files must be public.
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container, a `static+https://` URL of a web server or a `file://` URL of a local directory. Files must be public.
     ///
     /// Example: https://my-example-storage.com/my-bucket
     #[arg(short, long)]
     pub s3_url: Option<String>,
+
+    /// The number of files to process at once (defaults to 4 if omitted).
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// The Base64 encoded Ed25519 public key(s) of which one must have signed the version (comma separated). Remembered for the folder (defaults to the keys used for the folder before if omitted).
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_key: Vec<String>,
+
+    /// Accept versions without checking their signature.
+    #[arg(long)]
+    pub allow_unsigned: bool,
+
+    /// The size the local download cache is pruned to after switching, e.g. `500M` or `2G` (defaults to the `UPDTR_CACHE_MAX_SIZE` env var or 1G if omitted). 0 disables the cache.
+    #[arg(long, value_parser = parse_size)]
+    pub cache_max_size: Option<u64>,
+
+    /// Only print which files would be added, replaced and removed and how much would be downloaded, without changing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// The output format of `--dry-run`.
+    #[arg(long, value_enum, default_value_t = PlanFormat::Text, requires = "dry_run")]
+    pub format: PlanFormat,
 }
 
 #[derive(Args, Debug)]
@@ -95,9 +247,242 @@ pub struct UpdateArgs {
     #[arg(short, long)]
     pub output_dir: Option<String>,
 
-    /// The endpoint and bucket of the S3 (compatible) storage. Must currently use TLS with valid cert and files must be public.
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container, a `static+https://` URL of a web server or a `file://` URL of a local directory. Files must be public.
+    ///
+    /// Example: https://my-example-storage.com/my-bucket
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of files to process at once (defaults to 4 if omitted).
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// The Base64 encoded Ed25519 public key(s) of which one must have signed the version (comma separated). Remembered for the folder (defaults to the keys used for the folder before if omitted).
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_key: Vec<String>,
+
+    /// Accept versions without checking their signature.
+    #[arg(long)]
+    pub allow_unsigned: bool,
+
+    /// The size the local download cache is pruned to after switching, e.g. `500M` or `2G` (defaults to the `UPDTR_CACHE_MAX_SIZE` env var or 1G if omitted). 0 disables the cache.
+    #[arg(long, value_parser = parse_size)]
+    pub cache_max_size: Option<u64>,
+
+    /// Only print which files would be added, replaced and removed and how much would be downloaded, without changing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// The output format of `--dry-run`.
+    #[arg(long, value_enum, default_value_t = PlanFormat::Text, requires = "dry_run")]
+    pub format: PlanFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// The name of the version (defaults to the last installed version if omitted).
+    pub name: Option<String>,
+
+    /// The path prefix to prepend to all download paths.
+    #[arg(short('p'), long)]
+    pub filestore_path_prefix: Option<String>,
+
+    /// The directory to verify.
+    #[arg(short, long)]
+    pub output_dir: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container, a `static+https://` URL of a web server or a `file://` URL of a local directory. Files must be public.
     ///
     /// Example: https://my-example-storage.com/my-bucket
     #[arg(short, long)]
     pub s3_url: Option<String>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+pub struct PruneCacheArgs {
+    /// The size to prune the cache to, e.g. `500M` or `2G` (defaults to removing all cached files if omitted).
+    #[arg(long, value_parser = parse_size, default_value = "0")]
+    pub max_size: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct VersionsArgs {
+    /// The path prefix to prepend to all storage paths.
+    #[arg(short('p'), long)]
+    pub filestore_path_prefix: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container or a `file://` URL of a local directory (defaults to the S3 storage configured using the `AWS_*` environment variables if omitted). The storage must allow listing files, so credentials are taken from the environment like for `create`.
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of version definitions to download at once (defaults to 4 if omitted).
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// The output format.
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    pub format: ListFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct GcArgs {
+    /// The path prefix to prepend to all storage paths.
+    #[arg(short('p'), long)]
+    pub filestore_path_prefix: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container or a `file://` URL of a local directory (defaults to the S3 storage configured using the `AWS_*` environment variables if omitted). The storage must allow listing and deleting files, so credentials are taken from the environment like for `create`.
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of version definitions to download and files to remove at once (defaults to 4 if omitted).
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// Only report the files that would be removed and their size.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// The minimum age of an unreferenced file before it is removed, e.g. `12h` or `7d`, so files of versions that are still being created are kept.
+    #[arg(long, value_parser = parse_duration, default_value = "24h")]
+    pub grace_period: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// The name of the version to compare from.
+    pub from: String,
+
+    /// The name of the version to compare to.
+    pub to: String,
+
+    /// The path prefix to prepend to all storage paths.
+    #[arg(short('p'), long)]
+    pub filestore_path_prefix: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container or a `file://` URL of a local directory (defaults to the S3 storage configured using the `AWS_*` environment variables if omitted). Credentials are taken from the environment like for `create`.
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// The output format.
+    #[arg(long, value_enum, default_value_t = DiffFormat::Text)]
+    pub format: DiffFormat,
+}
+
+#[derive(Args, Debug)]
+pub struct PromoteArgs {
+    /// The name of the version (or of a channel, to promote the version it points to).
+    pub version: String,
+
+    /// The name of the channel, which is created if it doesn't exist yet.
+    pub channel: String,
+
+    /// The file containing the Base64 encoded Ed25519 secret key to sign the channel with (defaults to the key in the `UPDTR_SIGNING_KEY` env var, if set).
+    #[arg(long)]
+    pub signing_key: Option<String>,
+
+    /// The path prefix to prepend to all storage paths.
+    #[arg(short('p'), long)]
+    pub filestore_path_prefix: Option<String>,
+
+    /// The URL of the storage: the endpoint and bucket of the S3 (compatible) storage, an `azure://` URL of an Azure Blob Storage container or a `file://` URL of a local directory (defaults to the S3 storage configured using the `AWS_*` environment variables if omitted). Credentials are taken from the environment like for `create`.
+    #[arg(short, long)]
+    pub s3_url: Option<String>,
+
+    /// The number of times a storage operation that failed because of a transient error (like a dropped connection) is retried (defaults to the `UPDTR_RETRIES` env var or 3 if omitted).
+    #[arg(long)]
+    pub retries: Option<u32>,
+}
+
+/// Output formats of listings.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ListFormat {
+    /// A table for reading.
+    Table,
+
+    /// JSON for processing by other tools.
+    Json,
+}
+
+/// Output formats of the changes between versions.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum DiffFormat {
+    /// A list of changes for reading.
+    Text,
+
+    /// JSON for processing by other tools.
+    Json,
+
+    /// Markdown, e.g. for release notes.
+    Markdown,
+}
+
+/// Output formats of the plan of a switch.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PlanFormat {
+    /// A list of changes for reading.
+    Text,
+
+    /// JSON for processing by other tools.
+    Json,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_with_and_without_units() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size(" 3 kb "), Ok(3 << 10));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn parses_durations_with_and_without_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_duration(" 7d "), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("2w").is_err());
+        assert!(parse_duration("18446744073709551615d").is_err());
+    }
 }
@@ -0,0 +1,196 @@
+use std::{collections::HashSet, fs::{self, File}, io::{self, ErrorKind}, path::{Path, PathBuf}, sync::Mutex, time::SystemTime};
+
+use indicatif::HumanBytes;
+use snafu::{ResultExt, Whatever};
+
+use crate::cli;
+
+/// Suffix of blobs that are still being downloaded.
+const PART_SUFFIX: &str = ".part";
+
+/// A local cache of downloaded (compressed) blobs, so interrupted downloads can be resumed and switching back to a
+/// previous version doesn't download its files again.
+///
+/// Blobs are stored by their `u_sha256` and `c_algo`. Blobs that are still being downloaded get a `.part` suffix.
+pub struct BlobCache {
+    dir: PathBuf,
+    in_progress: Mutex<HashSet<String>>,
+}
+
+impl BlobCache {
+    pub fn open(dir: PathBuf) -> Result<BlobCache, Whatever> {
+        fs::create_dir_all(&dir).with_whatever_context(|_| format!("Could not create directory {:#?}", dir))?;
+        Ok(BlobCache { dir, in_progress: Mutex::new(HashSet::new()) })
+    }
+
+    /// Claims the cache entry of a blob for a download. Returns `None` if the blob is already being downloaded (e.g. when
+    /// a version contains the same file twice), in which case the download should bypass the cache.
+    pub fn claim(&self, u_sha256: &str, c_algo: &str) -> Option<CacheEntry<'_>> {
+        let name = format!("{u_sha256}.{c_algo}");
+        let mut in_progress = self.in_progress.lock().unwrap_or_else(|e| e.into_inner());
+        if !in_progress.insert(name.clone()) {
+            return None;
+        }
+
+        Some(CacheEntry { cache: self, name })
+    }
+
+    /// Removes the least recently used blobs (and partial downloads) until the cache is at most `max_size` bytes. Returns
+    /// the number of removed files and their total size.
+    pub fn prune(&self, max_size: u64) -> io::Result<(u32, u64)> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        files.sort();
+
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        let (mut n_removed, mut removed_size) = (0, 0);
+        for (_, len, path) in files {
+            if size <= max_size {
+                break;
+            }
+
+            fs::remove_file(path)?;
+            size -= len;
+            n_removed += 1;
+            removed_size += len;
+        }
+
+        Ok((n_removed, removed_size))
+    }
+}
+
+/// The cache entry of a single blob, claimed for downloading it. See [`BlobCache::claim`].
+pub struct CacheEntry<'a> {
+    cache: &'a BlobCache,
+    name: String,
+}
+
+impl CacheEntry<'_> {
+    /// Path of the completely downloaded blob.
+    pub fn blob_path(&self) -> PathBuf {
+        self.cache.dir.join(&self.name)
+    }
+
+    /// Path of the partially downloaded blob.
+    pub fn part_path(&self) -> PathBuf {
+        self.cache.dir.join(format!("{}{PART_SUFFIX}", self.name))
+    }
+
+    /// Size of the completely downloaded blob, `None` if there is none.
+    pub fn blob_len(&self) -> io::Result<Option<u64>> {
+        file_len(&self.blob_path())
+    }
+
+    /// Size of the partially downloaded blob, `None` if there is none.
+    pub fn part_len(&self) -> io::Result<Option<u64>> {
+        file_len(&self.part_path())
+    }
+
+    /// Marks the partially downloaded blob as complete.
+    pub fn complete(&self) -> io::Result<()> {
+        fs::rename(self.part_path(), self.blob_path())
+    }
+
+    /// Marks the blob as recently used, so it is pruned last.
+    pub fn touch(&self) -> io::Result<()> {
+        File::options().write(true).open(self.blob_path())?.set_modified(SystemTime::now())
+    }
+
+    /// Removes the (partially) downloaded blob, e.g. because it turned out to be corrupt.
+    pub fn discard(&self) -> io::Result<()> {
+        for path in [self.part_path(), self.blob_path()] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CacheEntry<'_> {
+    fn drop(&mut self) {
+        self.cache.in_progress.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.name);
+    }
+}
+
+/// Gets the size of a file, or `None` if it doesn't exist (which is different from an empty file, as blobs of empty files
+/// that are stored uncompressed are empty too).
+fn file_len(path: &Path) -> io::Result<Option<u64>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn run_prune_cache(cache_dir: PathBuf, max_size: u64) -> Result<(), Whatever> {
+    let cache = BlobCache::open(cache_dir)?;
+    let (n_removed, removed_size) = cache.prune(max_size).with_whatever_context(|_| format!("Could not prune cache {:#?}", cache.dir))?;
+
+    println!("{}Removed {} cached files ({}).", cli::CHECKMARK, n_removed, HumanBytes(removed_size));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn claims_each_blob_once() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::open(dir.path().to_owned()).unwrap();
+
+        let entry = cache.claim("abc", "zstd").unwrap();
+        assert!(cache.claim("abc", "zstd").is_none());
+        assert!(cache.claim("abc", "brotli").is_some());
+        drop(entry);
+        assert!(cache.claim("abc", "zstd").is_some());
+    }
+
+    #[test]
+    fn tells_missing_from_empty_blobs() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::open(dir.path().to_owned()).unwrap();
+        let entry = cache.claim("abc", "none").unwrap();
+        assert_eq!(entry.blob_len().unwrap(), None);
+        assert_eq!(entry.part_len().unwrap(), None);
+
+        fs::write(entry.part_path(), "").unwrap();
+        assert_eq!(entry.part_len().unwrap(), Some(0));
+        entry.complete().unwrap();
+        assert_eq!(entry.blob_len().unwrap(), Some(0));
+        assert_eq!(entry.part_len().unwrap(), None);
+
+        entry.discard().unwrap();
+        assert_eq!(entry.blob_len().unwrap(), None);
+    }
+
+    #[test]
+    fn prunes_least_recently_used_blobs() {
+        let dir = TempDir::new().unwrap();
+        let cache = BlobCache::open(dir.path().to_owned()).unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("old", 30), ("recent", 10), ("middle", 20)] {
+            let path = dir.path().join(name);
+            fs::write(&path, [0; 100]).unwrap();
+            File::options().write(true).open(&path).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+
+        assert_eq!(cache.prune(150).unwrap(), (2, 200));
+        assert!(dir.path().join("recent").exists());
+        assert_eq!(cache.prune(0).unwrap(), (1, 100));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, io::Cursor, path::{Path, PathBuf}};
+
+use bytes::Bytes;
+use chrono::Utc;
+use console::style;
+use ed25519_dalek::SigningKey;
+use futures::TryStreamExt;
+use snafu::{ResultExt, Whatever};
+
+use crate::{cli, commands::switch::{check_signature, get_version}, file_storage::{is_access_denied, FileStore}, models::channel::{Channel, ChannelTarget}, signing::{self, SIGNATURE_SUFFIX}};
+
+/// Path (relative to the storage base path) of the pointer of a channel.
+pub fn channel_path(storage_base_path: &str, channel_name: &str) -> PathBuf {
+    Path::new(storage_base_path).join("channels").join(channel_name)
+}
+
+/// Path (relative to the storage base path) of the signature of a channel.
+fn channel_signature_path(storage_base_path: &str, channel_name: &str) -> PathBuf {
+    Path::new(storage_base_path).join("channels").join(format!("{channel_name}{SIGNATURE_SUFFIX}"))
+}
+
+/// Downloads the raw channel, which is what its signature is made over (together with its name), or `None` if there is
+/// no channel with that name.
+async fn fetch_channel(stor_client: &impl FileStore, storage_base_path: &str, channel_name: &str) -> Result<Option<Vec<u8>>, Whatever> {
+    let channel_path = channel_path(storage_base_path, channel_name);
+    let Some(channel_file) = stor_client.get_file(&channel_path).await.with_whatever_context(|_| format!("Could not get file info for {:#?}", channel_path))? else {
+        return Ok(None);
+    };
+
+    Ok(Some(channel_file.stream.try_collect::<Vec<Bytes>>().await.with_whatever_context(|_| format!("Could not download {:#?}", channel_path))?.concat()))
+}
+
+fn parse_channel(channel_bytes: &[u8], channel_name: &str) -> Result<Channel, Whatever> {
+    serde_yml::from_slice(channel_bytes).with_whatever_context(|_| format!("Could not parse channel {channel_name}"))
+}
+
+/// Gets a channel, or `None` if there is no channel with that name.
+pub async fn get_channel(stor_client: &impl FileStore, storage_base_path: &str, channel_name: &str) -> Result<Option<Channel>, Whatever> {
+    fetch_channel(stor_client, storage_base_path, channel_name).await?.map(|channel_bytes| parse_channel(&channel_bytes, channel_name)).transpose()
+}
+
+/// A name resolved by [`resolve`].
+pub struct Resolved {
+    pub version_name: String,
+
+    /// The sequence number of the channel, if the name is the name of a channel.
+    pub channel_sequence: Option<u64>,
+}
+
+/// Resolves the name of a channel to the name of the version it points to. Any other name is taken to be the name of a
+/// version.
+///
+/// If `trusted_keys` is given, the channel must be signed by one of them, so nobody else can point it to another version.
+pub async fn resolve(stor_client: &impl FileStore, storage_base_path: &str, name: &str, trusted_keys: Option<&[String]>) -> Result<Resolved, Whatever> {
+    let version = Resolved { version_name: name.to_owned(), channel_sequence: None };
+    let channel_bytes = match fetch_channel(stor_client, storage_base_path, name).await {
+        Ok(Some(channel_bytes)) => channel_bytes,
+        Ok(None) => return Ok(version),
+        // Storage that can't be listed denies access to a channel that doesn't exist instead of reporting it as missing.
+        Err(e) if is_access_denied(&e) => return Ok(version),
+        Err(e) => return Err(e),
+    };
+
+    if let Some(trusted_keys) = trusted_keys {
+        let signature_path = channel_signature_path(storage_base_path, name);
+        check_signature(stor_client, &signature_path, signing::CHANNEL_KIND, name, &channel_bytes, trusted_keys).await?;
+    }
+
+    let channel = parse_channel(&channel_bytes, name)?;
+    Ok(Resolved { version_name: channel.current.version, channel_sequence: Some(channel.sequence) })
+}
+
+/// Resolves a name like [`resolve`], returning only the name of the version.
+pub async fn resolve_version_name(stor_client: &impl FileStore, storage_base_path: &str, name: &str, trusted_keys: Option<&[String]>) -> Result<String, Whatever> {
+    Ok(resolve(stor_client, storage_base_path, name, trusted_keys).await?.version_name)
+}
+
+/// Points a channel to a version, keeping the version it pointed to before in its history. The channel is signed if a
+/// signing key is given.
+pub async fn run_promote(version_name: String, channel_name: String, storage_base_path: String, signing_key: Option<SigningKey>, storage_client: impl FileStore) -> Result<(), Whatever> {
+    println!("{} {}Checking version...", style("[1/2]").bold().dim(), cli::LOOKING_GLASS);
+
+    // Promoting another channel promotes the version it points to, so the channel never points to a channel.
+    let resolved_name = resolve_version_name(&storage_client, &storage_base_path, &version_name, None).await?;
+    if resolved_name != version_name {
+        println!("      Channel {version_name} points to version {resolved_name}");
+    }
+    get_version(&storage_client, &storage_base_path, &resolved_name).await.with_whatever_context(|_| format!("Could not get info of version {resolved_name}"))?;
+
+    println!("{} {}Updating channel...", style("[2/2]").bold().dim(), cli::CHECKLIST);
+
+    let target = ChannelTarget { version: resolved_name.clone(), promoted_at: Utc::now() };
+    let (channel, previous_version) = match get_channel(&storage_client, &storage_base_path, &channel_name).await? {
+        Some(channel) if channel.current.version == resolved_name => {
+            println!("\n{}Channel {channel_name} already points to version {resolved_name}.", cli::CHECKMARK);
+            return Ok(());
+        },
+        Some(mut channel) => {
+            let previous_version = channel.current.version.clone();
+            channel.sequence += 1;
+            channel.history.insert(0, std::mem::replace(&mut channel.current, target));
+            (channel, Some(previous_version))
+        },
+        None => (Channel { sequence: 1, current: target, history: Vec::new() }, None),
+    };
+
+    let channel_path = channel_path(&storage_base_path, &channel_name);
+    let yaml_bytes = serde_yml::to_string(&channel).with_whatever_context(|_| "Could not convert channel to YAML")?.into_bytes();
+    storage_client.upload_file(&channel_path, Cursor::new(yaml_bytes.as_slice()), HashMap::new()).await.with_whatever_context(|_| format!("Could not upload file {:#?}", channel_path))?;
+
+    if let Some(signing_key) = &signing_key {
+        let signature_path = channel_signature_path(&storage_base_path, &channel_name);
+        let signature = signing::sign(signing_key, &signing::signed_document(signing::CHANNEL_KIND, &channel_name, &yaml_bytes));
+        storage_client.upload_file(&signature_path, Cursor::new(signature.as_bytes()), HashMap::new()).await.with_whatever_context(|_| format!("Could not upload file {:#?}", signature_path))?;
+        println!("      Signed with public key {}", signing::encode_public_key(&signing_key.verifying_key()));
+    }
+
+    match previous_version {
+        Some(previous_version) => println!("\n{}Channel {channel_name} now points to version {resolved_name} (was {previous_version}).", cli::CHECKMARK),
+        None => println!("\n{}Created channel {channel_name}, pointing to version {resolved_name}.", cli::CHECKMARK),
+    }
+
+    Ok(())
+}
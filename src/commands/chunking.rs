@@ -0,0 +1,112 @@
+use std::{fs::File, io::{self, BufReader, Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+
+use fastcdc::v2020::StreamCDC;
+
+/// Smallest size of a chunk.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Size chunks are aimed at.
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// Largest size of a chunk.
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Only files of at least this size are chunked, smaller files would only consist of a few chunks anyway.
+pub const MIN_CHUNKED_FILE_LEN: u64 = 4 * AVG_CHUNK_SIZE as u64;
+
+/// The position and hash of a chunk within a local file.
+pub struct LocalChunk {
+    pub offset: u64,
+    pub len: u64,
+    pub u_sha256: String,
+}
+
+/// Path (relative to the storage base path) of a chunk.
+pub fn chunk_path(storage_base_path: &str, u_sha256: &str) -> PathBuf {
+    Path::new(storage_base_path).join("chunks").join(u_sha256)
+}
+
+/// Splits a file into content-defined chunks, so inserting or removing data only changes the chunks around it instead of
+/// all chunks after it.
+pub fn chunk_file(path: &Path) -> io::Result<Vec<LocalChunk>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk?;
+        chunks.push(LocalChunk {
+            offset: chunk.offset,
+            len: chunk.length as u64,
+            u_sha256: sha256::digest(chunk.data.as_slice()),
+        });
+    }
+
+    Ok(chunks)
+}
+
+/// Reads a chunk of a local file into memory.
+pub fn read_chunk(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is shorter than expected"));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn random_data(seed: u64, len: usize) -> Vec<u8> {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        (0..len).map(|_| rng.u8(..)).collect()
+    }
+
+    #[test]
+    fn chunks_cover_the_whole_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("data.bin");
+        let data = random_data(1, 10 * 1024 * 1024);
+        fs::write(&path, &data).unwrap();
+
+        let chunks = chunk_file(&path).unwrap();
+        assert!(chunks.len() > 1);
+        let mut file = File::open(&path).unwrap();
+        let mut offset = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.len <= MAX_CHUNK_SIZE as u64);
+            let chunk_data = read_chunk(&mut file, chunk.offset, chunk.len).unwrap();
+            assert_eq!(sha256::digest(&chunk_data), chunk.u_sha256);
+            offset += chunk.len;
+        }
+        assert_eq!(offset, data.len() as u64);
+
+        assert_eq!(read_chunk(&mut file, offset - 10, 20).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn insertions_only_change_nearby_chunks() {
+        let dir = TempDir::new().unwrap();
+        let old_path = dir.path().join("old.bin");
+        let new_path = dir.path().join("new.bin");
+        let old_data = random_data(1, 10 * 1024 * 1024);
+        let mut new_data = old_data.clone();
+        new_data.splice(5_000_000..5_000_000, random_data(2, 1000));
+        fs::write(&old_path, &old_data).unwrap();
+        fs::write(&new_path, &new_data).unwrap();
+
+        let old_chunks: HashSet<String> = chunk_file(&old_path).unwrap().into_iter().map(|c| c.u_sha256).collect();
+        let new_chunks = chunk_file(&new_path).unwrap();
+        let changed = new_chunks.iter().filter(|c| !old_chunks.contains(&c.u_sha256)).count();
+        assert!((1..=2).contains(&changed), "{changed} of {} chunks changed", new_chunks.len());
+    }
+}
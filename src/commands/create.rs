@@ -1,115 +1,515 @@
-use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{BufReader, BufWriter, Cursor, ErrorKind, Write}, path::Path, sync::Mutex};
 
-use brotli::enc::BrotliEncoderParams;
-use bytes::BufMut;
-use console::style;
+use bytes::Bytes;
+use chrono::Utc;
+use console::{style, StyledObject};
+use ed25519_dalek::SigningKey;
+use futures::{stream, StreamExt, TryStreamExt};
 use indicatif::ProgressBar;
-use snafu::{OptionExt, ResultExt, Whatever};
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use tempfile::NamedTempFile;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::{cli, file_storage::FileStore, models::version_definition::*};
+use crate::{cli, compression::Codec, commands::{channel::resolve_version_name, chunking::{self, chunk_path, MIN_CHUNKED_FILE_LEN}, delta::{self, patch_name, patch_path, MAX_PATCH_FILE_LEN, PATCH_ALGO}, gc::{self, References}, switch::{file_mode, get_version}}, file_storage::{FileStore, RemoteFileInfo}, models::{local_state::STATE_DIR_NAME, version_definition::*}, signing};
 
-pub async fn run_create(display_version: Option<String>, version_names: &Vec<String>, input_dir: &str, storage_base_path: &str, storage_client: impl FileStore) -> Result<(), Whatever> {
-    println!("{} {}Building file list...", style("[1/3]").bold().dim(), cli::LOOKING_GLASS);
+/// A patch is only published if it is at most this fraction of the size of the compressed file, as applying a patch
+/// requires hashing and reading the whole local file.
+const MAX_PATCH_RATIO: f64 = 0.5;
 
-    let file_list: Vec<DirEntry> = WalkDir::new(input_dir)
+/// Options that influence how a version is created.
+pub struct CreateOptions {
+    /// The number of files to process at once.
+    pub jobs: usize,
+
+    /// The definition schema version to create the version definition in, or `None` to use the oldest one that can
+    /// represent the version.
+    pub definition_version: Option<DefinitionVersion>,
+
+    /// Names of previous versions to create patches from.
+    pub delta_from: Vec<String>,
+
+    /// The compression algorithms to try, each file is stored using the one that gives the smallest result.
+    pub codecs: Vec<Codec>,
+
+    /// Whether to store large files as content-defined chunks instead of as a whole.
+    pub chunked: bool,
+
+    /// The key to sign the version definition(s) with, if any.
+    pub signing_key: Option<SigningKey>,
+
+    /// The release notes to record in the version definition, if any.
+    pub release_notes: Option<String>,
+
+    /// The Git commit to record in the version definition, if any.
+    pub git_commit: Option<String>,
+
+    /// The build host to record in the version definition, if any.
+    pub build_host: Option<String>,
+
+    /// The oldest version of h3xup that can switch to the version, if any.
+    pub min_updater_version: Option<String>,
+
+    /// Whether to record the modification times of the files.
+    pub preserve_mtime: bool,
+}
+
+pub async fn run_create(display_version: Option<String>, version_names: &Vec<String>, input_dir: &str, storage_base_path: &str, options: CreateOptions, storage_client: impl FileStore) -> Result<(), Whatever> {
+    let n_steps = if options.delta_from.is_empty() { 3 } else { 4 };
+
+    println!("{} {}Building file list...", step(1, n_steps), cli::LOOKING_GLASS);
+
+    let entries: Vec<DirEntry> = WalkDir::new(input_dir)
+        .sort_by_file_name()
         .into_iter()
+        .filter_entry(|e| e.depth() != 1 || e.file_name() != STATE_DIR_NAME)
         .collect::<Result<Vec<_>, _>>()
-        .with_whatever_context(|_| format!("Failed to walk directory {}", input_dir))?
-        .into_iter()
-        .filter(|e| e.file_type().is_file())
-        .collect();
+        .with_whatever_context(|_| format!("Failed to walk directory {}", input_dir))?;
+
+    // Directories containing files are created along with their files, so only empty directories are recorded.
+    let mut directories = Vec::new();
+    for entry in entries.iter().filter(|e| e.depth() > 0 && e.file_type().is_dir()) {
+        let mut dir_entries = fs::read_dir(entry.path()).with_whatever_context(|_| format!("Could not read directory {:#?}", entry.path()))?;
+        if dir_entries.next().is_none() {
+            directories.push(relative_path(entry, input_dir)?);
+        }
+    }
+
+    let file_list: Vec<DirEntry> = entries.into_iter().filter(|e| e.file_type().is_file()).collect();
+
+    let mut previous_versions = Vec::new();
+    for name in &options.delta_from {
+        let version_name = resolve_version_name(&storage_client, storage_base_path, name, None).await.with_whatever_context(|_| format!("Could not resolve {name}"))?;
+        previous_versions.push(get_version(&storage_client, storage_base_path, &version_name).await.with_whatever_context(|_| format!("Could not get info of version {version_name}"))?);
+    }
+
+    // Stored files are only reused if a version references them (or this run uploaded them), see `get_reusable_file_info`.
+    let version_names_in_storage = gc::list_version_names(&storage_client, storage_base_path).await?;
+    let mut stored_versions = Vec::new();
+    for result in gc::get_versions(&storage_client, storage_base_path, &version_names_in_storage, options.jobs).await {
+        match result {
+            Ok(stored_version) => stored_versions.push(stored_version),
+            Err(e) => println!("      {}{e}, so the files it references are not reused", cli::WARNING),
+        }
+    }
+    let reusable = Mutex::new(References::of(&stored_versions));
 
     let mut version = VersionDefinition {
+        // Picked once the definition is complete.
         version: DefinitionVersion::Version1,
-        display_version: display_version,
+        display_version,
+        created_at: Some(Utc::now()),
+        release_notes: options.release_notes.clone(),
+        git_commit: options.git_commit.clone(),
+        build_host: options.build_host.clone(),
+        min_updater_version: options.min_updater_version.clone(),
         files: Vec::new(),
+        directories,
     };
 
     let pb = ProgressBar::new(file_list.len() as u64);
     pb.set_style(cli::PROGRESS_STYLE.clone());
 
-    println!("{} {}Processing {} files...", style("[2/3]").bold().dim(), cli::HOURGLASS, file_list.len());
-    let mut n_already_existing = 0; let mut n_uploaded = 0;
-    for entry in file_list {
-        let rel_file_path = entry
-            .path()
-            .strip_prefix(input_dir)
-            .with_whatever_context(|_| format!("Could not strip path prefix {:#?} of {:#?}", input_dir, entry.path()))?
-            .to_str()
-            .with_whatever_context(|| format!("Could not convert path {:#?} stripped of {:#?} to string", input_dir, entry.path()))?
-            .to_string();
-
-        pb.set_message(format!("Hashing {}", rel_file_path));
-
-        let uncompressed_sha256 = sha256::try_digest(entry.path()).with_whatever_context(|_| format!("Could not get SHA256 hash for file {:#?}", entry.path()))?;
-        let remote_path = Path::new(storage_base_path).join("files").join(uncompressed_sha256.clone());
-
-        pb.set_message(format!("Checking existing {}", rel_file_path));
-
-        // Check if file exists already.
-        let existing_file_info = storage_client.get_file_info(&remote_path).await.with_whatever_context(|_| format!("Could not get file info for {:#?}", remote_path))?;
-
-        let file = match existing_file_info {
-            Some(file_info) => {
-                // File already exists on remote storage.
-                n_already_existing = n_already_existing + 1;
-                FileDefinition {
-                    r_path: rel_file_path.clone(),
-                    u_len: entry.metadata().with_whatever_context(|_| format!("Could not get metadata of file {:#?}", remote_path))?.len() as u32,
-                    u_sha256: uncompressed_sha256.clone(),
-                    c_algo: "brotli".to_owned(),
-                    c_len: file_info.c_len,
-                    c_sha256: file_info.metadata.get("c_sha256").with_whatever_context(|| format!("c_sha256 missing of file {:#?}", remote_path))?.to_owned(),
+    println!("{} {}Processing {} files...", step(2, n_steps), cli::HOURGLASS, file_list.len());
+
+    // Process up to `jobs` files at once, while keeping the original file order.
+    let processed_files: Vec<(FileDefinition, bool)> = stream::iter(&file_list)
+        .map(|entry| async {
+            let processed_file = process_file(entry, input_dir, storage_base_path, &options, &storage_client, &reusable, &pb).await?;
+            pb.inc(1);
+            Ok::<_, Whatever>(processed_file)
+        })
+        .buffered(options.jobs)
+        .try_collect()
+        .await?;
+
+    let n_uploaded = processed_files.iter().filter(|(_, uploaded)| *uploaded).count();
+    let n_already_existing = processed_files.len() - n_uploaded;
+    version.files = processed_files.into_iter().map(|(file, _)| file).collect();
+
+    pb.finish_and_clear();
+
+    let mut n_patches = 0;
+    if !previous_versions.is_empty() {
+        let mut previous_files_by_path: HashMap<&str, Vec<&FileDefinition>> = HashMap::new();
+        for previous_file in previous_versions.iter().flat_map(|v| &v.files) {
+            previous_files_by_path.entry(&previous_file.r_path).or_default().push(previous_file);
+        }
+
+        // Every file that changed compared to a previous version gets a patch from each distinct previous hash.
+        let mut candidates = Vec::new();
+        for (index, (entry, file)) in file_list.iter().zip(&version.files).enumerate() {
+            let mut from_hashes = HashSet::new();
+            // Patches are not created for chunked files, as these already only download the chunks that changed.
+            if !file.chunks.is_empty() {
+                continue;
+            }
+
+            let previous_files = previous_files_by_path.get(file.r_path.as_str()).into_iter().flatten();
+            for previous_file in previous_files.filter(|f| f.u_sha256 != file.u_sha256 && f.chunks.is_empty()) {
+                if from_hashes.insert(&previous_file.u_sha256) && file.u_len < MAX_PATCH_FILE_LEN && previous_file.u_len < MAX_PATCH_FILE_LEN {
+                    candidates.push((index, entry, file, previous_file));
                 }
-            },
-            None => {
-                // File needs to be uploaded.
-                pb.set_message(format!("Compressing {}", rel_file_path));
-                n_uploaded = n_uploaded + 1;
-                let file = File::open(entry.path()).with_whatever_context(|_| format!("Could not open file {:#?}", entry.path()))?;
-                let mut reader = BufReader::new(file);
-                let mut buf: bytes::buf::Writer<Vec<u8>> = Vec::new().writer();
-
-                // Compress using Brotli.
-                let mut params = BrotliEncoderParams::default();
-                params.quality = 8;
-                brotli::BrotliCompress(&mut reader, &mut buf, &params).with_whatever_context(|_| format!("Could not compress file {:#?}", entry.path()))?;
-                let compressed = buf.into_inner();
-                let compressed_sha256 = sha256::digest(&compressed);
-
-                pb.set_message(format!("Uploading {}", rel_file_path));
-                storage_client.upload_file(remote_path.as_path(), compressed.as_slice(), HashMap::from([
-                    ("c_algo", "brotli"),
-                    ("c_sha256", &compressed_sha256),
-                ])).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path))?;
-
-                FileDefinition {
-                    r_path: rel_file_path.clone(),
-                    u_len: entry.metadata().with_whatever_context(|_| format!("Could not get metadata of file {:#?}", remote_path))?.len() as u32,
-                    u_sha256: uncompressed_sha256.clone(),
-                    c_algo: "brotli".to_owned(),
-                    c_len: compressed.len() as u32,
-                    c_sha256: compressed_sha256.clone(),
+            }
+        }
+
+        let pb = ProgressBar::new(candidates.len() as u64);
+        pb.set_style(cli::PROGRESS_STYLE.clone());
+
+        println!("{} {}Creating {} patches...", step(3, n_steps), cli::HOURGLASS, candidates.len());
+        let patches: Vec<(usize, Option<PatchDefinition>)> = stream::iter(candidates)
+            .map(|(index, entry, file, previous_file)| {
+                let storage_client = &storage_client;
+                let reusable = &reusable;
+                let pb = &pb;
+                async move {
+                    let patch = process_patch(entry, file, previous_file, storage_base_path, storage_client, reusable, pb).await?;
+                    pb.inc(1);
+                    Ok::<_, Whatever>((index, patch))
                 }
-            },
-        };
+            })
+            .buffered(options.jobs)
+            .try_collect()
+            .await?;
+
+        pb.finish_and_clear();
 
-        version.files.push(file);
-        pb.inc(1);
+        for (index, patch) in patches {
+            if let Some(patch) = patch {
+                version.files[index].patches.push(patch);
+                n_patches += 1;
+            }
+        }
     }
 
-    pb.finish_and_clear();
+    // Unless asked otherwise, use the oldest schema version the definition allows, so older versions of h3xup can read it.
+    let oldest_supporting = DefinitionVersion::oldest_supporting(&version);
+    version.version = match options.definition_version {
+        Some(definition_version) if (definition_version as u32) < (oldest_supporting as u32) => {
+            whatever!("The version requires definition schema version {} or later, as older versions of h3xup would not switch to it correctly", oldest_supporting as u32)
+        },
+        Some(definition_version) => definition_version,
+        None => oldest_supporting,
+    };
 
-    println!("{} {}Uploading version definition(s)...", style("[3/3]").bold().dim(), cli::CHECKLIST);
+    println!("{} {}Uploading version definition(s)...", step(n_steps, n_steps), cli::CHECKLIST);
     let yaml_bytes = serde_yml::to_string(&version).with_whatever_context(|_| "Could not convert version info to YAML")?.into_bytes();
     for version_name in version_names {
         let remote_path = Path::new(storage_base_path).join("versions").join(version_name);
-        let mut yaml_cursor = std::io::Cursor::new(&yaml_bytes);
-        storage_client.upload_file(remote_path.as_path(), &mut yaml_cursor, HashMap::new()).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path.as_path()))?;
+        storage_client.upload_file(remote_path.as_path(), Cursor::new(yaml_bytes.as_slice()), HashMap::new()).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path.as_path()))?;
+
+        if let Some(signing_key) = &options.signing_key {
+            let signature_path = signing::signature_path(storage_base_path, version_name);
+            let signature = signing::sign(signing_key, &signing::signed_document(signing::VERSION_KIND, version_name, &yaml_bytes));
+            storage_client.upload_file(signature_path.as_path(), Cursor::new(signature.as_bytes()), HashMap::new()).await.with_whatever_context(|_| format!("Could not upload file {:#?}", signature_path.as_path()))?;
+        }
+    }
+    if let Some(signing_key) = &options.signing_key {
+        println!("      Signed with public key {}", signing::encode_public_key(&signing_key.verifying_key()));
     }
 
-    println!("\n{}Successfully finished with {} already existing and {} uploaded files.", cli::CHECKMARK, n_already_existing, n_uploaded);
+    if options.delta_from.is_empty() {
+        println!("\n{}Successfully finished with {} already existing and {} uploaded files.", cli::CHECKMARK, n_already_existing, n_uploaded);
+    } else {
+        println!("\n{}Successfully finished with {} already existing and {} uploaded files and {} patches.", cli::CHECKMARK, n_already_existing, n_uploaded, n_patches);
+    }
+
+    Ok(())
+}
+
+fn step(n: u32, n_steps: u32) -> StyledObject<String> {
+    style(format!("[{n}/{n_steps}]")).bold().dim()
+}
+
+/// Gets the path of a walked entry relative to the input directory.
+fn relative_path(entry: &DirEntry, input_dir: &str) -> Result<String, Whatever> {
+    Ok(entry
+        .path()
+        .strip_prefix(input_dir)
+        .with_whatever_context(|_| format!("Could not strip path prefix {:#?} of {:#?}", input_dir, entry.path()))?
+        .to_str()
+        .with_whatever_context(|| format!("Could not convert path {:#?} stripped of {:#?} to string", input_dir, entry.path()))?
+        .to_string())
+}
+
+/// Hashes a file and uploads it if it does not exist in the storage yet. Returns its definition and whether it was uploaded.
+async fn process_file(entry: &DirEntry, input_dir: &str, storage_base_path: &str, options: &CreateOptions, storage_client: &impl FileStore, reusable: &Mutex<References>, pb: &ProgressBar) -> Result<(FileDefinition, bool), Whatever> {
+    let definition_version = options.definition_version;
+
+    let rel_file_path = relative_path(entry, input_dir)?;
+    let metadata = entry.metadata().with_whatever_context(|_| format!("Could not get metadata of file {:#?}", entry.path()))?;
+    let u_len = metadata.len();
+    check_file_len(&rel_file_path, u_len, definition_version)?;
+    let mode = file_mode(&metadata);
+    let mtime = match options.preserve_mtime {
+        true => Some(metadata.modified().with_whatever_context(|_| format!("Could not get modification time of file {:#?}", entry.path()))?.into()),
+        false => None,
+    };
+
+    pb.set_message(format!("Hashing {}", rel_file_path));
+
+    let local_path = entry.path().to_owned();
+    let uncompressed_sha256 = tokio::task::spawn_blocking(move || sha256::try_digest(local_path)).await
+        .with_whatever_context(|_| format!("Could not hash file {:#?}", entry.path()))?
+        .with_whatever_context(|_| format!("Could not get SHA256 hash for file {:#?}", entry.path()))?;
+
+    if options.chunked && u_len >= MIN_CHUNKED_FILE_LEN {
+        let (chunks, uploaded) = process_chunks(entry.path(), storage_base_path, &options.codecs, storage_client, reusable, pb).await?;
+        return Ok((FileDefinition {
+            r_path: rel_file_path,
+            u_len,
+            u_sha256: uncompressed_sha256,
+            c_algo: CHUNKED_ALGO.to_owned(),
+            c_len: chunks.iter().map(|c| c.c_len).sum(),
+            c_sha256: None,
+            mode,
+            mtime,
+            patches: Vec::new(),
+            chunks,
+        }, uploaded));
+    }
+
+    let remote_path = Path::new(storage_base_path).join("files").join(uncompressed_sha256.clone());
+
+    pb.set_message(format!("Checking existing {}", rel_file_path));
+
+    // Check if file exists already.
+    let is_referenced = reusable.lock().unwrap().files.contains(&uncompressed_sha256);
+    let existing_file_info = get_reusable_file_info(storage_client, &remote_path, is_referenced).await?;
+
+    if let Some(file_info) = existing_file_info {
+        // File already exists on remote storage, keep it with whatever algorithm it was compressed with.
+        check_file_len(&rel_file_path, file_info.c_len, definition_version)?;
+        return Ok((FileDefinition {
+            r_path: rel_file_path,
+            u_len,
+            u_sha256: uncompressed_sha256,
+            c_algo: file_info.metadata.get("c_algo").with_whatever_context(|| format!("c_algo missing of file {:#?}", remote_path))?.to_owned(),
+            c_len: file_info.c_len,
+            c_sha256: Some(file_info.metadata.get("c_sha256").with_whatever_context(|| format!("c_sha256 missing of file {:#?}", remote_path))?.to_owned()),
+            mode,
+            mtime,
+            patches: Vec::new(),
+            chunks: Vec::new(),
+        }, false));
+    }
+
+    // File needs to be uploaded, compress it to a temporary file with each algorithm on the blocking pool as this is CPU
+    // bound, and keep the smallest result.
+    pb.set_message(format!("Compressing {}", rel_file_path));
+    let local_path = entry.path().to_owned();
+    let codecs = options.codecs.clone();
+    let (codec, compressed_file, c_len, compressed_sha256) = tokio::task::spawn_blocking(move || {
+        let mut smallest: Option<(Codec, NamedTempFile, u64)> = None;
+        for codec in codecs {
+            let mut reader = BufReader::new(File::open(&local_path)?);
+            let mut compressed_file = NamedTempFile::new()?;
+
+            let mut writer = BufWriter::new(compressed_file.as_file_mut());
+            codec.compress(&mut reader, &mut writer)?;
+            writer.flush()?;
+            drop(writer);
+
+            let c_len = compressed_file.as_file().metadata()?.len();
+            if smallest.as_ref().is_none_or(|(_, _, smallest_len)| c_len < *smallest_len) {
+                smallest = Some((codec, compressed_file, c_len));
+            }
+        }
+
+        let (codec, compressed_file, c_len) = smallest.ok_or_else(|| std::io::Error::other("No compression algorithm given"))?;
+        let compressed_sha256 = sha256::try_digest(compressed_file.path())?;
+        Ok::<_, std::io::Error>((codec, compressed_file, c_len, compressed_sha256))
+    }).await
+        .with_whatever_context(|_| format!("Could not compress file {:#?}", entry.path()))?
+        .with_whatever_context(|_| format!("Could not compress file {:#?}", entry.path()))?;
+
+    check_file_len(&rel_file_path, c_len, definition_version)?;
+
+    pb.set_message(format!("Uploading {}", rel_file_path));
+    let compressed_reader = tokio::fs::File::open(compressed_file.path()).await.with_whatever_context(|_| format!("Could not open compressed file {:#?}", compressed_file.path()))?;
+    storage_client.upload_file(remote_path.as_path(), compressed_reader, HashMap::from([
+        ("c_algo", codec.name()),
+        ("c_sha256", &compressed_sha256),
+    ])).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path))?;
+    reusable.lock().unwrap().files.insert(uncompressed_sha256.clone());
+
+    Ok((FileDefinition {
+        r_path: rel_file_path,
+        u_len,
+        u_sha256: uncompressed_sha256,
+        c_algo: codec.name().to_owned(),
+        c_len,
+        c_sha256: Some(compressed_sha256),
+        mode,
+        mtime,
+        patches: Vec::new(),
+        chunks: Vec::new(),
+    }, true))
+}
+
+/// Splits a file into content-defined chunks and uploads the ones that don't exist in the storage yet. Returns their
+/// definitions and whether any chunk was uploaded.
+async fn process_chunks(local_path: &Path, storage_base_path: &str, codecs: &[Codec], storage_client: &impl FileStore, reusable: &Mutex<References>, pb: &ProgressBar) -> Result<(Vec<ChunkDefinition>, bool), Whatever> {
+    pb.set_message(format!("Chunking {:#?}", local_path));
+    let local_chunks = tokio::task::spawn_blocking({
+        let local_path = local_path.to_owned();
+        move || chunking::chunk_file(&local_path)
+    }).await
+        .with_whatever_context(|_| format!("Could not chunk file {:#?}", local_path))?
+        .with_whatever_context(|_| format!("Could not chunk file {:#?}", local_path))?;
+
+    let mut chunks = Vec::with_capacity(local_chunks.len());
+    let mut uploaded = false;
+    for local_chunk in local_chunks {
+        let remote_path = chunk_path(storage_base_path, &local_chunk.u_sha256);
+
+        // Check if chunk exists already, e.g. as part of another file or version.
+        let is_referenced = reusable.lock().unwrap().chunks.contains(&local_chunk.u_sha256);
+        if let Some(chunk_info) = get_reusable_file_info(storage_client, &remote_path, is_referenced).await? {
+            chunks.push(ChunkDefinition {
+                u_len: local_chunk.len,
+                u_sha256: local_chunk.u_sha256,
+                c_algo: chunk_info.metadata.get("c_algo").with_whatever_context(|| format!("c_algo missing of file {:#?}", remote_path))?.to_owned(),
+                c_len: chunk_info.c_len,
+                c_sha256: chunk_info.metadata.get("c_sha256").with_whatever_context(|| format!("c_sha256 missing of file {:#?}", remote_path))?.to_owned(),
+            });
+            continue;
+        }
+
+        // Chunks are small enough to be compressed in memory.
+        let (codec, compressed_data) = tokio::task::spawn_blocking({
+            let local_path = local_path.to_owned();
+            let codecs = codecs.to_vec();
+            move || {
+                let data = chunking::read_chunk(&mut File::open(local_path)?, local_chunk.offset, local_chunk.len)?;
+
+                let mut smallest: Option<(Codec, Vec<u8>)> = None;
+                for codec in codecs {
+                    let mut compressed_data = Vec::new();
+                    codec.compress(&mut data.as_slice(), &mut compressed_data)?;
+                    if smallest.as_ref().is_none_or(|(_, smallest_data)| compressed_data.len() < smallest_data.len()) {
+                        smallest = Some((codec, compressed_data));
+                    }
+                }
+                smallest.ok_or_else(|| std::io::Error::other("No compression algorithm given"))
+            }
+        }).await
+            .with_whatever_context(|_| format!("Could not compress chunk of file {:#?}", local_path))?
+            .with_whatever_context(|_| format!("Could not compress chunk of file {:#?}", local_path))?;
+
+        let compressed_sha256 = sha256::digest(compressed_data.as_slice());
+        storage_client.upload_file(remote_path.as_path(), Cursor::new(compressed_data.as_slice()), HashMap::from([
+            ("c_algo", codec.name()),
+            ("c_sha256", &compressed_sha256),
+        ])).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path))?;
+        reusable.lock().unwrap().chunks.insert(local_chunk.u_sha256.clone());
+
+        chunks.push(ChunkDefinition {
+            u_len: local_chunk.len,
+            u_sha256: local_chunk.u_sha256,
+            c_algo: codec.name().to_owned(),
+            c_len: compressed_data.len() as u64,
+            c_sha256: compressed_sha256,
+        });
+        uploaded = true;
+    }
+
+    Ok((chunks, uploaded))
+}
+
+/// Creates a patch from a file of a previous version and uploads it if it doesn't exist in the storage yet. Returns its
+/// definition, or `None` if the patch is not small enough compared to the compressed file to be worth publishing.
+async fn process_patch(entry: &DirEntry, file: &FileDefinition, previous_file: &FileDefinition, storage_base_path: &str, storage_client: &impl FileStore, reusable: &Mutex<References>, pb: &ProgressBar) -> Result<Option<PatchDefinition>, Whatever> {
+    let remote_path = patch_path(storage_base_path, &previous_file.u_sha256, &file.u_sha256);
+
+    pb.set_message(format!("Checking existing patch of {}", file.r_path));
+    let name = patch_name(&previous_file.u_sha256, &file.u_sha256);
+    let is_referenced = reusable.lock().unwrap().patches.contains(&name);
+    if let Some(patch_info) = get_reusable_file_info(storage_client, &remote_path, is_referenced).await? {
+        return Ok(Some(PatchDefinition {
+            from_u_sha256: previous_file.u_sha256.clone(),
+            p_algo: PATCH_ALGO.to_owned(),
+            p_len: patch_info.c_len,
+            p_sha256: patch_info.metadata.get("p_sha256").with_whatever_context(|| format!("p_sha256 missing of file {:#?}", remote_path))?.to_owned(),
+        }));
+    }
+
+    pb.set_message(format!("Downloading previous {}", file.r_path));
+    let previous_data = download_uncompressed(previous_file, storage_base_path, storage_client).await?;
+
+    // The patch is created from the whole previous file in memory, on the blocking pool as this is CPU bound.
+    pb.set_message(format!("Creating patch of {}", file.r_path));
+    let local_path = entry.path().to_owned();
+    let (patch_file, p_len, patch_sha256) = tokio::task::spawn_blocking(move || {
+        let mut patch_file = NamedTempFile::new()?;
+        delta::create_patch(&previous_data, &local_path, patch_file.as_file_mut())?;
+
+        let p_len = patch_file.as_file().metadata()?.len();
+        let patch_sha256 = sha256::try_digest(patch_file.path())?;
+        Ok::<_, std::io::Error>((patch_file, p_len, patch_sha256))
+    }).await
+        .with_whatever_context(|_| format!("Could not create patch for file {:#?}", entry.path()))?
+        .with_whatever_context(|_| format!("Could not create patch for file {:#?}", entry.path()))?;
+
+    if p_len as f64 > file.c_len as f64 * MAX_PATCH_RATIO {
+        return Ok(None);
+    }
+
+    pb.set_message(format!("Uploading patch of {}", file.r_path));
+    let patch_reader = tokio::fs::File::open(patch_file.path()).await.with_whatever_context(|_| format!("Could not open patch file {:#?}", patch_file.path()))?;
+    storage_client.upload_file(remote_path.as_path(), patch_reader, HashMap::from([
+        ("p_algo", PATCH_ALGO),
+        ("p_sha256", &patch_sha256),
+    ])).await.with_whatever_context(|_| format!("Could not upload file {:#?}", remote_path))?;
+    reusable.lock().unwrap().patches.insert(name);
+
+    Ok(Some(PatchDefinition {
+        from_u_sha256: previous_file.u_sha256.clone(),
+        p_algo: PATCH_ALGO.to_owned(),
+        p_len,
+        p_sha256: patch_sha256,
+    }))
+}
+
+/// Gets the info of a stored file (or chunk or patch) if it can be reused instead of uploaded, which is only the case if it
+/// is referenced by a version (or was uploaded by this run). A `gc` running at the same time could remove an old
+/// unreferenced file before the version definition that references it is uploaded, so those are uploaded again instead,
+/// which makes them new.
+async fn get_reusable_file_info(storage_client: &impl FileStore, remote_path: &Path, is_referenced: bool) -> Result<Option<RemoteFileInfo>, Whatever> {
+    if !is_referenced {
+        return Ok(None);
+    }
+
+    storage_client.get_file_info(remote_path).await.with_whatever_context(|_| format!("Could not get file info for {:#?}", remote_path))
+}
+
+/// Downloads a stored file and decompresses it into memory.
+async fn download_uncompressed(file: &FileDefinition, storage_base_path: &str, storage_client: &impl FileStore) -> Result<Vec<u8>, Whatever> {
+    let remote_path = Path::new(storage_base_path).join("files").join(&file.u_sha256);
+    let remote_file = storage_client.get_file(&remote_path).await
+        .with_whatever_context(|_| format!("Could not get file info for {:#?}", remote_path))?.with_whatever_context(|| format!("Could not find file {:#?}", remote_path))?;
+    let codec = Codec::from_name(&file.c_algo).with_whatever_context(|| format!("Compression algorithm {} of file {:#?} is not supported", file.c_algo, remote_path))?;
+    let compressed_data: Vec<u8> = remote_file.stream.try_collect::<Vec<Bytes>>().await.with_whatever_context(|_| format!("Could not download {:#?}", remote_path))?.concat();
+
+    let expected_sha256 = file.u_sha256.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut decompressor = codec.decompressor(Vec::new())?;
+        decompressor.write_all(&compressed_data)?;
+        let data = decompressor.finish()?;
+        if sha256::digest(data.as_slice()) != expected_sha256 {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "Decompressed data does not match its SHA256 hash"));
+        }
+        Ok(data)
+    }).await
+        .with_whatever_context(|_| format!("Could not decompress {:#?}", remote_path))?
+        .with_whatever_context(|_| format!("Could not decompress {:#?}", remote_path))
+}
+
+/// Refuses file sizes that can't be represented in the definition schema version (if one was given), instead of silently
+/// truncating them.
+fn check_file_len(rel_file_path: &str, len: u64, definition_version: Option<DefinitionVersion>) -> Result<(), Whatever> {
+    if let Some(definition_version) = definition_version && len > definition_version.max_file_len() {
+        whatever!("File {rel_file_path} is too large for definition schema version {}", definition_version as u32);
+    }
 
     Ok(())
 }
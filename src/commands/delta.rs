@@ -0,0 +1,91 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+
+use zstd::{stream::{raw, write::{Decoder, Encoder}}, zstd_safe::DParameter};
+
+/// Algorithm used for creating patches: Zstandard with the old file as reference prefix.
+pub const PATCH_ALGO: &str = "zstd";
+
+/// Zstandard compression level used for patches.
+const PATCH_LEVEL: i32 = 9;
+
+/// Largest window the patches may use (256 MiB), both the old and new file must fit in it to find all matches.
+const MAX_WINDOW_LOG: u32 = 28;
+
+/// Patches are only created if both the old and the new file are smaller than this. Creating and applying a patch keeps
+/// the whole old file (and the window) in memory, for up to `--jobs` files at once, so this is kept moderate.
+pub const MAX_PATCH_FILE_LEN: u64 = 1 << MAX_WINDOW_LOG;
+
+/// Path (relative to the storage base path) of the patch from one uncompressed file to another.
+pub fn patch_path(storage_base_path: &str, from_u_sha256: &str, to_u_sha256: &str) -> PathBuf {
+    Path::new(storage_base_path).join("patches").join(patch_name(from_u_sha256, to_u_sha256))
+}
+
+/// File name of the patch from one uncompressed file to another.
+pub fn patch_name(from_u_sha256: &str, to_u_sha256: &str) -> String {
+    format!("{from_u_sha256}_{to_u_sha256}")
+}
+
+/// Writes a patch to `patch_file` that turns `old` into the file at `new_path`.
+pub fn create_patch(old: &[u8], new_path: &Path, patch_file: &mut File) -> io::Result<()> {
+    let new_file = File::open(new_path)?;
+    let new_len = new_file.metadata()?.len();
+
+    let mut encoder = Encoder::with_ref_prefix(BufWriter::new(patch_file), PATCH_LEVEL, old)?;
+    encoder.window_log(window_log(new_len.max(old.len() as u64)))?;
+    encoder.long_distance_matching(true)?;
+    encoder.include_checksum(true)?;
+
+    io::copy(&mut BufReader::new(new_file), &mut encoder)?;
+    encoder.finish()?.flush()
+}
+
+/// Returns a writer that applies the patch written to it to `old` and writes the result to `writer`.
+pub fn patch_writer<W: Write>(old: &[u8], writer: W) -> io::Result<Decoder<'_, W>> {
+    let mut decoder = raw::Decoder::with_ref_prefix(old)?;
+    decoder.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG))?;
+    Ok(Decoder::with_decoder(writer, decoder))
+}
+
+/// The smallest window log (at least 10, the minimum of Zstandard) whose window covers `len` bytes.
+fn window_log(len: u64) -> u32 {
+    (u64::BITS - len.leading_zeros()).clamp(10, MAX_WINDOW_LOG)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek};
+
+    use super::*;
+
+    #[test]
+    fn patches_turn_old_into_new_file() {
+        let old: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut new = old.clone();
+        new.splice(1000..1000, b"inserted".iter().copied());
+        new.truncate(300_000);
+        let mut new_file = tempfile::NamedTempFile::new().unwrap();
+        new_file.write_all(&new).unwrap();
+
+        let mut patch_file = tempfile::tempfile().unwrap();
+        create_patch(&old, new_file.path(), &mut patch_file).unwrap();
+        let mut patch = Vec::new();
+        patch_file.rewind().unwrap();
+        patch_file.read_to_end(&mut patch).unwrap();
+        assert!(patch.len() < 1000);
+
+        let mut patched = Vec::new();
+        let mut writer = patch_writer(&old, &mut patched).unwrap();
+        writer.write_all(&patch).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn window_covers_both_files() {
+        assert_eq!(window_log(0), 10);
+        assert_eq!(window_log(1 << 20), 21);
+        assert_eq!(window_log((1 << 20) - 1), 20);
+        assert_eq!(window_log(u64::MAX), MAX_WINDOW_LOG);
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+
+use console::style;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use snafu::{ResultExt, Whatever};
+
+use crate::{cli::DiffFormat, commands::{channel::resolve_version_name, switch::get_version}, file_storage::FileStore, models::version_definition::{FileDefinition, VersionDefinition, CHUNKED_ALGO}};
+
+/// The differences between two versions, as reported by the `diff` command.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionDiff {
+    from: String,
+    to: String,
+    from_display_version: Option<String>,
+    to_display_version: Option<String>,
+    added: Vec<FileSummary>,
+    removed: Vec<FileSummary>,
+    modified: Vec<Modification>,
+    renamed: Vec<Rename>,
+    /// The number of bytes a client on `from` downloads when switching to `to`.
+    download_len: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileSummary {
+    path: String,
+    u_len: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Modification {
+    path: String,
+    from_u_len: u64,
+    to_u_len: u64,
+}
+
+/// A file that was moved without changing its contents.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Rename {
+    from_path: String,
+    to_path: String,
+    u_len: u64,
+}
+
+pub async fn run_diff(storage_base_path: String, from: String, to: String, format: DiffFormat, storage_client: impl FileStore) -> Result<(), Whatever> {
+    let (from, to) = tokio::try_join!(
+        async { resolve_version_name(&storage_client, &storage_base_path, &from, None).await.with_whatever_context(|_| format!("Could not resolve {from}")) },
+        async { resolve_version_name(&storage_client, &storage_base_path, &to, None).await.with_whatever_context(|_| format!("Could not resolve {to}")) },
+    )?;
+    let (from_def, to_def) = tokio::try_join!(
+        async { get_version(&storage_client, &storage_base_path, &from).await.with_whatever_context(|_| format!("Could not get info of version {from}")) },
+        async { get_version(&storage_client, &storage_base_path, &to).await.with_whatever_context(|_| format!("Could not get info of version {to}")) },
+    )?;
+
+    let diff = diff_versions(from, to, from_def, to_def);
+    match format {
+        DiffFormat::Text => print_text(&diff),
+        DiffFormat::Json => {
+            let json = serde_json::to_string_pretty(&diff).with_whatever_context(|_| "Could not convert differences to JSON")?;
+            println!("{json}");
+        },
+        DiffFormat::Markdown => print_markdown(&diff),
+    }
+
+    Ok(())
+}
+
+fn diff_versions(from: String, to: String, from_def: VersionDefinition, to_def: VersionDefinition) -> VersionDiff {
+    let from_files: HashMap<&str, &FileDefinition> = from_def.files.iter().map(|f| (f.r_path.as_str(), f)).collect();
+    let to_files: HashMap<&str, &FileDefinition> = to_def.files.iter().map(|f| (f.r_path.as_str(), f)).collect();
+
+    let mut modified = Vec::new();
+    let mut removed: Vec<&FileDefinition> = Vec::new();
+    for file in &from_def.files {
+        match to_files.get(file.r_path.as_str()) {
+            Some(to_file) if to_file.u_sha256 != file.u_sha256 => modified.push(Modification { path: file.r_path.clone(), from_u_len: file.u_len, to_u_len: to_file.u_len }),
+            Some(_) => {},
+            None => removed.push(file),
+        }
+    }
+    let mut added: Vec<&FileDefinition> = to_def.files.iter().filter(|f| !from_files.contains_key(f.r_path.as_str())).collect();
+
+    // An added file with the same contents as a removed file is a rename. Each removed file is matched at most once.
+    let mut renamed = Vec::new();
+    let mut removed_by_sha256: HashMap<&str, Vec<&FileDefinition>> = HashMap::new();
+    for file in removed.iter().rev() {
+        removed_by_sha256.entry(file.u_sha256.as_str()).or_default().push(file);
+    }
+    added.retain(|file| match removed_by_sha256.get_mut(file.u_sha256.as_str()).and_then(Vec::pop) {
+        Some(from_file) => {
+            renamed.push(Rename { from_path: from_file.r_path.clone(), to_path: file.r_path.clone(), u_len: file.u_len });
+            false
+        },
+        None => true,
+    });
+    let renamed_from: HashSet<&str> = renamed.iter().map(|r| r.from_path.as_str()).collect();
+    removed.retain(|file| !renamed_from.contains(file.r_path.as_str()));
+
+    VersionDiff {
+        download_len: download_len(&from_def, &to_def),
+        from,
+        to,
+        from_display_version: from_def.display_version.clone(),
+        to_display_version: to_def.display_version.clone(),
+        added: added.into_iter().map(|f| FileSummary { path: f.r_path.clone(), u_len: f.u_len }).collect(),
+        removed: removed.into_iter().map(|f| FileSummary { path: f.r_path.clone(), u_len: f.u_len }).collect(),
+        modified,
+        renamed,
+    }
+}
+
+/// Sums up the compressed sizes of what a switch from `from` to `to` downloads. Like a switch, only the file at the same
+/// path is reused: a changed file with a patch from its previous contents counts with the size of the patch, a chunked
+/// file with the size of the chunks its previous contents don't have, and any other changed, renamed or added file as a
+/// whole.
+fn download_len(from_def: &VersionDefinition, to_def: &VersionDefinition) -> u64 {
+    let from_files: HashMap<&str, &FileDefinition> = from_def.files.iter().map(|f| (f.r_path.as_str(), f)).collect();
+
+    let mut download_len = 0;
+    for file in &to_def.files {
+        let previous_file = from_files.get(file.r_path.as_str());
+        if previous_file.is_some_and(|f| f.u_sha256 == file.u_sha256) {
+            continue;
+        }
+
+        if file.c_algo == CHUNKED_ALGO {
+            let previous_chunks: HashSet<&str> = previous_file.iter().flat_map(|f| &f.chunks).map(|c| c.u_sha256.as_str()).collect();
+            download_len += file.chunks.iter().filter(|c| !previous_chunks.contains(c.u_sha256.as_str())).map(|c| c.c_len).sum::<u64>();
+            continue;
+        }
+
+        let previous_sha256 = previous_file.map(|f| f.u_sha256.as_str());
+        download_len += match file.patches.iter().find(|p| Some(p.from_u_sha256.as_str()) == previous_sha256) {
+            Some(patch) => patch.p_len,
+            None => file.c_len,
+        };
+    }
+
+    download_len
+}
+
+fn version_title(name: &str, display_version: &Option<String>) -> String {
+    match display_version {
+        Some(display_version) => format!("{name} ({display_version})"),
+        None => name.to_owned(),
+    }
+}
+
+fn print_text(diff: &VersionDiff) {
+    println!("{}", style(format!("Changes from {} to {}", version_title(&diff.from, &diff.from_display_version), version_title(&diff.to, &diff.to_display_version))).bold());
+
+    for file in &diff.added {
+        println!("{} {} ({})", style("+").green(), file.path, HumanBytes(file.u_len));
+    }
+    for file in &diff.removed {
+        println!("{} {}", style("-").red(), file.path);
+    }
+    for file in &diff.modified {
+        println!("{} {} ({} → {})", style("~").yellow(), file.path, HumanBytes(file.from_u_len), HumanBytes(file.to_u_len));
+    }
+    for file in &diff.renamed {
+        println!("{} {} → {}", style(">").cyan(), file.from_path, file.to_path);
+    }
+
+    println!("\n{} added, {} removed, {} modified and {} renamed files, {} to download.", diff.added.len(), diff.removed.len(), diff.modified.len(), diff.renamed.len(), HumanBytes(diff.download_len));
+}
+
+fn print_markdown(diff: &VersionDiff) {
+    println!("## Changes from {} to {}", version_title(&diff.from, &diff.from_display_version), version_title(&diff.to, &diff.to_display_version));
+    println!("\n{} added, {} removed, {} modified and {} renamed files, {} to download.", diff.added.len(), diff.removed.len(), diff.modified.len(), diff.renamed.len(), HumanBytes(diff.download_len));
+
+    let sections: [(&str, Vec<String>); 4] = [
+        ("Added", diff.added.iter().map(|f| format!("`{}` ({})", f.path, HumanBytes(f.u_len))).collect()),
+        ("Removed", diff.removed.iter().map(|f| format!("`{}`", f.path)).collect()),
+        ("Modified", diff.modified.iter().map(|f| format!("`{}` ({} → {})", f.path, HumanBytes(f.from_u_len), HumanBytes(f.to_u_len))).collect()),
+        ("Renamed", diff.renamed.iter().map(|f| format!("`{}` → `{}`", f.from_path, f.to_path)).collect()),
+    ];
+    for (title, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+        println!("\n### {title}\n");
+        for line in lines {
+            println!("- {line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::version_definition::{ChunkDefinition, DefinitionVersion, PatchDefinition};
+
+    use super::*;
+
+    fn file(r_path: &str, u_sha256: &str, c_len: u64) -> FileDefinition {
+        FileDefinition {
+            r_path: r_path.to_owned(),
+            u_len: c_len * 2,
+            u_sha256: u_sha256.to_owned(),
+            c_algo: "br".to_owned(),
+            c_len,
+            c_sha256: Some(format!("c_{u_sha256}")),
+            mode: None,
+            mtime: None,
+            patches: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    fn chunked_file(r_path: &str, u_sha256: &str, chunks: &[(&str, u64)]) -> FileDefinition {
+        FileDefinition {
+            c_algo: CHUNKED_ALGO.to_owned(),
+            c_len: chunks.iter().map(|(_, c_len)| c_len).sum(),
+            c_sha256: None,
+            chunks: chunks.iter().map(|(u_sha256, c_len)| ChunkDefinition {
+                u_len: c_len * 2,
+                u_sha256: (*u_sha256).to_owned(),
+                c_algo: "zstd".to_owned(),
+                c_len: *c_len,
+                c_sha256: format!("c_{u_sha256}"),
+            }).collect(),
+            ..file(r_path, u_sha256, 0)
+        }
+    }
+
+    fn version(files: Vec<FileDefinition>) -> VersionDefinition {
+        VersionDefinition {
+            version: DefinitionVersion::Version3,
+            display_version: None,
+            created_at: None,
+            release_notes: None,
+            git_commit: None,
+            build_host: None,
+            min_updater_version: None,
+            files,
+            directories: Vec::new(),
+        }
+    }
+
+    fn paths(files: &[FileSummary]) -> Vec<&str> {
+        files.iter().map(|f| f.path.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_added_removed_modified_and_renamed_files() {
+        let from = version(vec![file("same.txt", "1", 10), file("changed.txt", "2", 20), file("old_name.txt", "3", 30), file("removed.txt", "4", 40)]);
+        let to = version(vec![file("same.txt", "1", 10), file("changed.txt", "5", 50), file("new_name.txt", "3", 30), file("added.txt", "6", 60)]);
+
+        let diff = diff_versions("from".to_owned(), "to".to_owned(), from, to);
+
+        assert_eq!(paths(&diff.added), ["added.txt"]);
+        assert_eq!(paths(&diff.removed), ["removed.txt"]);
+        assert_eq!(diff.modified.iter().map(|f| (f.path.as_str(), f.from_u_len, f.to_u_len)).collect::<Vec<_>>(), [("changed.txt", 40, 100)]);
+        assert_eq!(diff.renamed.iter().map(|f| (f.from_path.as_str(), f.to_path.as_str())).collect::<Vec<_>>(), [("old_name.txt", "new_name.txt")]);
+    }
+
+    #[test]
+    fn matches_each_removed_file_to_one_rename() {
+        let from = version(vec![file("a.txt", "1", 10)]);
+        let to = version(vec![file("b.txt", "1", 10), file("c.txt", "1", 10)]);
+
+        let diff = diff_versions("from".to_owned(), "to".to_owned(), from, to);
+
+        assert_eq!(diff.renamed.len(), 1);
+        assert_eq!(diff.added.len(), 1);
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn counts_downloads_like_a_switch() {
+        let mut patched = file("patched.txt", "2", 50);
+        patched.patches.push(PatchDefinition { from_u_sha256: "1".to_owned(), p_algo: "zstd".to_owned(), p_len: 5, p_sha256: "p".to_owned() });
+        let from = version(vec![
+            file("same.txt", "0", 1000),
+            file("patched.txt", "1", 40),
+            file("old_name.txt", "3", 30),
+            chunked_file("big.bin", "4", &[("a", 100), ("b", 200)]),
+        ]);
+        let to = version(vec![
+            file("same.txt", "0", 1000),
+            patched,
+            // Renamed and duplicated contents are downloaded again, as a switch only reuses the file at the same path.
+            file("new_name.txt", "3", 30),
+            file("copy.txt", "0", 1000),
+            chunked_file("big.bin", "5", &[("a", 100), ("c", 300)]),
+        ]);
+
+        let diff = diff_versions("from".to_owned(), "to".to_owned(), from, to);
+
+        assert_eq!(diff.download_len, 5 + 30 + 1000 + 300);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use console::{style, StyledObject};
+use futures::{stream, StreamExt, TryStreamExt};
+use indicatif::{HumanBytes, HumanDuration, ProgressBar};
+use snafu::{ResultExt, Whatever};
+
+use crate::{cli, commands::{delta::patch_name, switch::get_version}, file_storage::FileStore, models::version_definition::VersionDefinition, signing::SIGNATURE_SUFFIX};
+
+pub struct GcOptions {
+    /// Only report what would be removed.
+    pub dry_run: bool,
+
+    /// Unreferenced files are only removed once they are at least this old, so files uploaded by a `create` that is still
+    /// running (and whose version definition doesn't exist yet) are kept. `create` only reuses files that are referenced
+    /// by a version, so it never relies on an old unreferenced file.
+    pub grace_period: Duration,
+
+    pub jobs: usize,
+}
+
+/// Names of the stored files, chunks and patches that are referenced by versions.
+#[derive(Default)]
+pub struct References {
+    pub files: HashSet<String>,
+    pub chunks: HashSet<String>,
+    pub patches: HashSet<String>,
+}
+
+impl References {
+    pub fn of<'a>(versions: impl IntoIterator<Item = &'a VersionDefinition>) -> References {
+        let mut references = References::default();
+        for file in versions.into_iter().flat_map(|v| &v.files) {
+            references.files.insert(file.u_sha256.clone());
+            references.chunks.extend(file.chunks.iter().map(|c| c.u_sha256.clone()));
+            references.patches.extend(file.patches.iter().map(|p| patch_name(&p.from_u_sha256, &file.u_sha256)));
+        }
+
+        references
+    }
+}
+
+/// A storage directory with the names of the files in it that are referenced by a version.
+struct Category {
+    dir: &'static str,
+    referenced: HashSet<String>,
+}
+
+/// An unreferenced file that is old enough to be removed.
+struct Candidate {
+    path: PathBuf,
+    c_len: u64,
+}
+
+pub async fn run_gc(storage_base_path: String, options: GcOptions, storage_client: impl FileStore) -> Result<(), Whatever> {
+    let n_steps = if options.dry_run { 2 } else { 3 };
+
+    println!("{} {}Loading version definitions...", step(1, n_steps), cli::LOOKING_GLASS);
+
+    let version_names = list_version_names(&storage_client, &storage_base_path).await?;
+
+    // Every definition has to be read, as a version that can't be read (e.g. because it uses a newer definition schema)
+    // may reference any file.
+    let versions: Vec<VersionDefinition> = get_versions(&storage_client, &storage_base_path, &version_names, options.jobs).await
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .with_whatever_context(|_| "Could not read all versions, so the files they reference are unknown")?;
+
+    let references = References::of(&versions);
+    // Signatures are only kept together with their version.
+    let mut referenced_versions = HashSet::new();
+    for name in &version_names {
+        referenced_versions.insert(name.to_string());
+        referenced_versions.insert(format!("{name}{SIGNATURE_SUFFIX}"));
+    }
+    let categories = [
+        Category { dir: "files", referenced: references.files },
+        Category { dir: "chunks", referenced: references.chunks },
+        Category { dir: "patches", referenced: references.patches },
+        Category { dir: "versions", referenced: referenced_versions },
+    ];
+
+    println!("      Found {} versions", versions.len());
+    println!("{} {}Finding unreferenced files...", step(2, n_steps), cli::LOOKING_GLASS);
+
+    let now = SystemTime::now();
+    let mut candidates = Vec::new();
+    let mut n_too_recent = 0;
+    for category in &categories {
+        let dir = Path::new(&storage_base_path).join(category.dir);
+        let entries = storage_client.list(&dir).await.with_whatever_context(|_| format!("Could not list {:#?}", dir))?;
+
+        let mut n_candidates = 0;
+        let mut c_len = 0;
+        for entry in entries.into_iter().filter(|e| !category.referenced.contains(&e.name)) {
+            // Files of unknown age are treated as new, as they could belong to a version that is still being created.
+            let age = entry.last_modified.and_then(|t| now.duration_since(t).ok());
+            if age.is_none_or(|age| age < options.grace_period) {
+                n_too_recent += 1;
+                continue;
+            }
+
+            n_candidates += 1;
+            c_len += entry.c_len;
+            candidates.push(Candidate { path: dir.join(&entry.name), c_len: entry.c_len });
+        }
+
+        println!("      {}: {} unreferenced ({})", category.dir, n_candidates, HumanBytes(c_len));
+    }
+
+    if n_too_recent > 0 {
+        println!("      Kept {} unreferenced files younger than {}", n_too_recent, HumanDuration(options.grace_period));
+    }
+
+    let total_len: u64 = candidates.iter().map(|c| c.c_len).sum();
+    if options.dry_run {
+        println!("\n{}Dry run, {} files ({}) can be removed.", cli::CHECKMARK, candidates.len(), HumanBytes(total_len));
+        return Ok(());
+    }
+
+    let pb = ProgressBar::new(candidates.len() as u64);
+    pb.set_style(cli::PROGRESS_STYLE.clone());
+
+    println!("{} {}Removing {} files...", step(3, n_steps), cli::HOURGLASS, candidates.len());
+
+    stream::iter(&candidates)
+        .map(|candidate| {
+            let storage_client = &storage_client;
+            let pb = &pb;
+            async move {
+                storage_client.delete(&candidate.path).await.with_whatever_context(|_| format!("Could not remove {:#?}", candidate.path))?;
+                pb.inc(1);
+                Ok::<_, Whatever>(())
+            }
+        })
+        .buffer_unordered(options.jobs)
+        .try_collect::<()>()
+        .await?;
+
+    pb.finish_and_clear();
+
+    println!("\n{}Successfully removed {} files ({}).", cli::CHECKMARK, candidates.len(), HumanBytes(total_len));
+
+    Ok(())
+}
+
+/// Lists the names of all versions in the storage.
+pub async fn list_version_names(storage_client: &impl FileStore, storage_base_path: &str) -> Result<Vec<String>, Whatever> {
+    let versions_dir = Path::new(storage_base_path).join("versions");
+    let version_entries = storage_client.list(&versions_dir).await.with_whatever_context(|_| format!("Could not list versions in {:#?}", versions_dir))?;
+    Ok(version_entries.into_iter().map(|e| e.name).filter(|name| !name.ends_with(SIGNATURE_SUFFIX)).collect())
+}
+
+/// Reads the definitions of versions, up to `jobs` at once.
+pub async fn get_versions(storage_client: &impl FileStore, storage_base_path: &str, version_names: &[String], jobs: usize) -> Vec<Result<VersionDefinition, Whatever>> {
+    stream::iter(version_names)
+        .map(|name| async move {
+            get_version(storage_client, storage_base_path, name).await
+                .with_whatever_context(|_| format!("Could not read version {name}"))
+        })
+        .buffered(jobs)
+        .collect()
+        .await
+}
+
+fn step(n: u32, n_steps: u32) -> StyledObject<String> {
+    style(format!("[{n}/{n_steps}]")).bold().dim()
+}
@@ -0,0 +1,122 @@
+use std::io::{self, ErrorKind, Write};
+
+use sha2::{Digest, Sha256};
+
+/// Number of times a file is downloaded before giving up if it doesn't pass its integrity check.
+pub const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Incrementally checks data against its expected size and SHA256 hash.
+///
+/// Data that is shorter than expected results in an error of kind [`ErrorKind::UnexpectedEof`] (e.g. an interrupted
+/// download that may be resumed), all other errors are of kind [`ErrorKind::InvalidData`], so they can be told apart from
+/// other I/O errors.
+pub struct Checker {
+    what: &'static str,
+    expected_len: u64,
+    expected_sha256: Option<String>,
+    len: u64,
+    hasher: Sha256,
+}
+
+impl Checker {
+    /// Creates a checker for the data described by `what` (used in error messages). The hash is not checked if it is `None`.
+    pub fn new(what: &'static str, expected_len: u64, expected_sha256: Option<&str>) -> Checker {
+        Checker { what, expected_len, expected_sha256: expected_sha256.map(str::to_owned), len: 0, hasher: Sha256::new() }
+    }
+
+    /// Adds the next part of the data. Fails as soon as there is more data than expected.
+    pub fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        self.len += data.len() as u64;
+        if self.len > self.expected_len {
+            return Err(integrity_error(format!("{} is larger than the expected {} bytes", self.what, self.expected_len)));
+        }
+
+        self.hasher.update(data);
+        Ok(())
+    }
+
+    /// Checks the size and hash of all data that was added. Larger data was already reported by [`Checker::update`].
+    pub fn finish(self) -> io::Result<()> {
+        if self.len < self.expected_len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("{} is {} bytes instead of the expected {} bytes", self.what, self.len, self.expected_len)));
+        }
+
+        if let Some(expected_sha256) = self.expected_sha256 {
+            let sha256 = format!("{:x}", self.hasher.finalize());
+            if sha256 != expected_sha256 {
+                return Err(integrity_error(format!("{} has SHA256 hash {sha256} instead of the expected {expected_sha256}", self.what)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks data that is in memory as a whole, see [`Checker`].
+pub fn check(what: &'static str, data: &[u8], expected_len: u64, expected_sha256: &str) -> io::Result<()> {
+    let mut checker = Checker::new(what, expected_len, Some(expected_sha256));
+    checker.update(data)?;
+    checker.finish()
+}
+
+/// A writer that checks all data written through it, see [`Checker`].
+pub struct CheckedWriter<W: Write> {
+    inner: W,
+    checker: Checker,
+}
+
+impl<W: Write> CheckedWriter<W> {
+    pub fn new(inner: W, checker: Checker) -> CheckedWriter<W> {
+        CheckedWriter { inner, checker }
+    }
+
+    /// Checks all data written and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.checker.finish()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for CheckedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checker.update(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn integrity_error(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn accepts_expected_data() {
+        check("Data", b"hello", 5, HELLO_SHA256).unwrap();
+
+        let mut writer = CheckedWriter::new(Vec::new(), Checker::new("Data", 5, Some(HELLO_SHA256)));
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo").unwrap();
+        assert_eq!(writer.finish().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn tells_incomplete_from_corrupt_data() {
+        assert_eq!(check("Data", b"hell", 5, HELLO_SHA256).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(check("Data", b"hello!", 5, HELLO_SHA256).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(check("Data", b"jello", 5, HELLO_SHA256).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut writer = CheckedWriter::new(Vec::new(), Checker::new("Data", 5, None));
+        writer.write_all(b"jello").unwrap();
+        writer.finish().unwrap();
+    }
+}
@@ -1,2 +1,13 @@
 pub mod create;
 pub mod switch;
+pub mod verify;
+pub mod cache;
+pub mod versions;
+pub mod gc;
+pub mod diff;
+pub mod channel;
+mod chunking;
+mod delta;
+mod integrity;
+mod plan;
+mod transaction;
@@ -0,0 +1,166 @@
+use std::{collections::HashSet, path::Path};
+
+use console::style;
+use indicatif::{HumanBytes, ProgressBar};
+use serde::Serialize;
+use snafu::{ResultExt, Whatever};
+
+use crate::{cli::{self, PlanFormat}, commands::switch::{chunk_local_file, find_obsolete_files, get_file_statuses, get_switchable_version, load_local_state, FileStatus, SwitchOptions}, file_storage::FileStore, models::{local_state::{JOURNAL_FILE_NAME, STATE_DIR_NAME}, version_definition::{FileDefinition, CHUNKED_ALGO}}};
+
+/// What a switch would change, as printed by `switch --dry-run`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SwitchPlan {
+    version: String,
+    display_version: Option<String>,
+    added: Vec<PlannedFile>,
+    replaced: Vec<PlannedFile>,
+    removed: Vec<String>,
+    /// Empty directories of the version that don't exist yet.
+    added_directories: Vec<String>,
+    /// Files of which only the permissions or modification time are fixed.
+    metadata_changed: Vec<String>,
+    unchanged_count: usize,
+    release_notes: Option<String>,
+    /// The number of (compressed) bytes to download.
+    download_len: u64,
+    /// The number of (uncompressed) bytes to write.
+    write_len: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlannedFile {
+    path: String,
+    u_len: u64,
+    download_len: u64,
+    /// Whether the file is patched instead of downloaded as a whole.
+    patched: bool,
+}
+
+/// Compares the local files to a version like a switch does, but prints what would be changed instead of changing it.
+pub async fn run_plan(version_name: String, output_dir: String, storage_base_path: String, format: PlanFormat, options: &SwitchOptions, storage_client: impl FileStore) -> Result<(), Whatever> {
+    if Path::new(&output_dir).join(STATE_DIR_NAME).join(JOURNAL_FILE_NAME).exists() {
+        eprintln!("{}An interrupted switch will be rolled back before switching, the plan is based on the files as they are now.", cli::WARNING);
+    }
+
+    let version_def = get_switchable_version(&storage_client, &storage_base_path, &version_name, options).await?;
+
+    let pb = ProgressBar::new(version_def.files.len() as u64);
+    pb.set_style(cli::PROGRESS_STYLE.clone());
+    pb.set_message("Verifying existing files");
+    let statuses = get_file_statuses(&version_def, &output_dir, options.jobs, &pb).await?;
+    pb.finish_and_clear();
+
+    let mut plan = SwitchPlan {
+        version: version_name,
+        display_version: version_def.display_version.clone(),
+        added: Vec::new(),
+        replaced: Vec::new(),
+        removed: Vec::new(),
+        added_directories: version_def.directories.iter().filter(|r_path| !Path::new(&output_dir).join(r_path).is_dir()).cloned().collect(),
+        metadata_changed: Vec::new(),
+        unchanged_count: 0,
+        release_notes: version_def.release_notes.clone(),
+        download_len: 0,
+        write_len: 0,
+    };
+    for (file, status) in version_def.files.iter().zip(statuses) {
+        let (exists, local_sha256) = match status {
+            FileStatus::Unchanged => {
+                plan.unchanged_count += 1;
+                continue;
+            },
+            FileStatus::MetadataChanged => {
+                plan.metadata_changed.push(file.r_path.clone());
+                continue;
+            },
+            FileStatus::Changed(local_sha256) => (true, local_sha256),
+            FileStatus::Missing => (false, None),
+        };
+
+        let local_path = Path::new(&output_dir).join(&file.r_path);
+        let planned_file = plan_file(file, exists.then_some(local_path.as_path()), local_sha256.as_deref()).await?;
+        plan.download_len += planned_file.download_len;
+        plan.write_len += planned_file.u_len;
+        match exists {
+            true => plan.replaced.push(planned_file),
+            false => plan.added.push(planned_file),
+        }
+    }
+
+    if let Some(previous_state) = load_local_state(&output_dir)? {
+        plan.removed = find_obsolete_files(&previous_state, &version_def, &output_dir).into_iter().map(str::to_owned).collect();
+    }
+
+    match format {
+        PlanFormat::Text => print_plan(&plan),
+        PlanFormat::Json => {
+            let json = serde_json::to_string_pretty(&plan).with_whatever_context(|_| "Could not convert plan to JSON")?;
+            println!("{json}");
+        },
+    }
+
+    Ok(())
+}
+
+/// Works out how a file would be downloaded: using a patch from the local file, only the chunks the local file doesn't
+/// have, or as a whole.
+async fn plan_file(file: &FileDefinition, local_path: Option<&Path>, local_sha256: Option<&str>) -> Result<PlannedFile, Whatever> {
+    let patch = local_sha256.and_then(|local_sha256| file.patches.iter().find(|p| p.from_u_sha256 == local_sha256));
+    let download_len = match patch {
+        Some(patch) => patch.p_len,
+        None if file.c_algo == CHUNKED_ALGO => {
+            let local_chunks = chunk_local_file(local_path).await?;
+            let local_chunks: HashSet<&str> = local_chunks.iter().map(|c| c.u_sha256.as_str()).collect();
+            file.chunks.iter().filter(|c| !local_chunks.contains(c.u_sha256.as_str())).map(|c| c.c_len).sum()
+        },
+        None => file.c_len,
+    };
+
+    Ok(PlannedFile { path: file.r_path.clone(), u_len: file.u_len, download_len, patched: patch.is_some() })
+}
+
+fn print_plan(plan: &SwitchPlan) {
+    match &plan.display_version {
+        Some(display_version) => println!("Switching to version {} ({display_version}) would change:", plan.version),
+        None => println!("Switching to version {} would change:", plan.version),
+    }
+
+    print_file_list("Add", &plan.added);
+    print_file_list("Replace", &plan.replaced);
+    print_path_list("Remove", &plan.removed);
+    print_path_list("Fix permissions or modification time", &plan.metadata_changed);
+    print_path_list("Create directory", &plan.added_directories);
+
+    println!("\n{}Dry run, {} added, {} replaced, {} removed, {} fixed and {} unchanged files: {} to download and {} to write.",
+        cli::CHECKMARK, plan.added.len(), plan.replaced.len(), plan.removed.len(), plan.metadata_changed.len(), plan.unchanged_count, HumanBytes(plan.download_len), HumanBytes(plan.write_len));
+
+    if let Some(release_notes) = &plan.release_notes {
+        println!("\n{}", style("Release notes:").bold());
+        println!("{}", release_notes.trim_end());
+    }
+}
+
+fn print_file_list(title: &str, files: &[PlannedFile]) {
+    if files.is_empty() {
+        return;
+    }
+
+    println!("\n{} ({}):", style(title).bold(), files.len());
+    for file in files {
+        let patched = if file.patched { ", patched" } else { "" };
+        println!("  {} ({}, {} to download{patched})", file.path, HumanBytes(file.u_len), HumanBytes(file.download_len));
+    }
+}
+
+fn print_path_list(title: &str, r_paths: &[String]) {
+    if r_paths.is_empty() {
+        return;
+    }
+
+    println!("\n{} ({}):", style(title).bold(), r_paths.len());
+    for r_path in r_paths {
+        println!("  {r_path}");
+    }
+}
@@ -1,98 +1,776 @@
-use std::{fs::{self, File}, io::{Cursor, ErrorKind}, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufWriter, ErrorKind, Read, Write}, path::{Path, PathBuf}};
 
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use console::style;
-use futures::StreamExt;
+use futures::{stream::{self, BoxStream}, StreamExt, TryStreamExt};
 use indicatif::{MultiProgress, ProgressBar};
-use snafu::{OptionExt, ResultExt, Whatever};
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use tokio::sync::mpsc;
 
-use crate::{cli, file_storage::FileStore, models::version_definition::*};
+use crate::{cli::{self, PlanFormat}, compression::Codec, commands::{cache::{BlobCache, CacheEntry}, channel, chunking::{self, chunk_path, LocalChunk}, delta::{self, patch_path, PATCH_ALGO}, integrity::{self, CheckedWriter, Checker, MAX_DOWNLOAD_ATTEMPTS}, plan, transaction::Transaction}, file_storage::FileStore, models::{local_state::*, version_definition::*}, signing};
 
-pub async fn run_switch(version_name: String, output_dir: String, storage_base_path: String, storage_client: impl FileStore) -> Result<(), Whatever> {
-    println!("{} {}Getting file list...", style("[1/2]").bold().dim(), cli::LOOKING_GLASS);
+/// Number of downloaded chunks that may be waiting for decompression.
+const DECOMPRESS_QUEUE_LEN: usize = 16;
 
-    let version_def = get_version(&storage_client, &storage_base_path, &version_name).await.with_whatever_context(|_| format!("Could not get info of version {version_name}"))?;
+/// Size of the buffer used for reading files from the blob cache.
+const CACHE_READ_BUFFER_SIZE: usize = 64 * 1024;
 
+/// Number of chunks of a chunked file that may be waiting to be written, kept low as they are held in memory as a whole.
+const CHUNK_QUEUE_LEN: usize = 4;
+
+/// Options that influence how a switch is performed.
+pub struct SwitchOptions {
+    /// The number of files to process at once.
+    pub jobs: usize,
+
+    /// Base64 encoded public keys of which one must have signed the version definition.
+    pub trusted_keys: Vec<String>,
+
+    /// Whether to accept version definitions without checking their signature.
+    pub allow_unsigned: bool,
+
+    /// The directory of the blob cache, or `None` to not cache downloads.
+    pub cache_dir: Option<PathBuf>,
+
+    /// The size the blob cache is pruned to after switching.
+    pub cache_max_size: u64,
+
+    /// Only print what a switch would change, in the given format, without changing anything.
+    pub dry_run: Option<PlanFormat>,
+}
+
+/// Switches a folder to a version, or to the version a channel points to if `name` is the name of a channel.
+pub async fn run_switch(name: String, output_dir: String, storage_base_path: String, options: SwitchOptions, storage_client: impl FileStore) -> Result<(), Whatever> {
+    let trusted_keys = (!options.allow_unsigned).then_some(options.trusted_keys.as_slice());
+    let resolved = channel::resolve(&storage_client, &storage_base_path, &name, trusted_keys).await.with_whatever_context(|_| format!("Could not resolve {name}"))?;
+    let version_name = resolved.version_name;
+    if let Some(channel_sequence) = resolved.channel_sequence {
+        check_channel_sequence(load_local_state(&output_dir)?.as_ref(), &name, channel_sequence)?;
+    }
+    if let Some(format) = options.dry_run {
+        return plan::run_plan(version_name, output_dir, storage_base_path, format, &options, storage_client).await;
+    }
+
+    let (mut transaction, recovered) = Transaction::begin(&output_dir)?;
+    if recovered {
+        println!("{}Rolled back changes of an interrupted switch.", cli::WARNING);
+    }
+
+    println!("{} {}Getting file list...", style("[1/3]").bold().dim(), cli::LOOKING_GLASS);
+    if version_name != name {
+        println!("      Channel {name} points to version {version_name}");
+    }
+
+    let version_def = get_switchable_version(&storage_client, &storage_base_path, &version_name, &options).await?;
+
+    let cache = options.cache_dir.clone().map(BlobCache::open).transpose()?;
     let multi_progress = MultiProgress::new();
 
     let pb = multi_progress.add(ProgressBar::new(version_def.files.len() as u64));
     pb.set_style(cli::PROGRESS_STYLE.clone());
 
-    println!("{} {}Processing {} files...", style("[2/2]").bold().dim(), cli::HOURGLASS, version_def.files.len());
-    let mut n_unchanged = 0; let mut n_changed = 0; let mut n_missing = 0;
-    for file in version_def.files {
-        pb.set_message(format!("Verifying {}", file.r_path));
-
-        let full_path = Path::new(&output_dir).join(&file.r_path);
-        let existing_file = fs::metadata(&full_path);
-        let existing_file = match existing_file {
-            Ok(f) if !f.is_file() => panic!("Expected a file"),
-            Ok(f) => Some(f),
-            Err(e) if e.kind() == ErrorKind::NotFound => None,
-            _ => panic!("Unknown error"),
-        };
+    println!("{} {}Processing {} files...", style("[2/3]").bold().dim(), cli::HOURGLASS, version_def.files.len());
+    pb.set_message("Verifying existing files");
 
-        match existing_file {
-            None => {
-                pb.set_message(format!("Restoring missing {}", file.r_path));
-                n_missing = n_missing + 1;
-                download_file(&file, full_path, &storage_client, &storage_base_path).await?;
-            }
-            Some(existing_file) => {
-                // Destination file exists, first check file size (which is cheap to check).
-                if existing_file.len() != file.u_len as u64 {
-                    // Size check indicates different file.
-                    pb.set_message(format!("Updating {}", file.r_path));
-                    n_changed = n_changed + 1;
-                    download_file(&file, full_path, &storage_client, &storage_base_path).await?;
-                } else {
-                    let sha256 = sha256::try_digest(&full_path).with_whatever_context(|_| format!("Could not get SHA256 hash for file {:#?}", full_path))?;
-                    if sha256 != file.u_sha256 {
-                        // Contents check indicates different file.
-                        pb.set_message(format!("Updating {}", file.r_path));
-                        n_changed = n_changed + 1;
-                        download_file(&file, full_path, &storage_client, &storage_base_path).await?;
-                    } else {
-                        n_unchanged = n_unchanged + 1;
+    let statuses = get_file_statuses(&version_def, &output_dir, options.jobs, &pb).await?;
+
+    let n_unchanged = statuses.iter().filter(|s| **s == FileStatus::Unchanged).count();
+    let n_changed = statuses.iter().filter(|s| matches!(s, FileStatus::Changed(_) | FileStatus::MetadataChanged)).count();
+    let n_missing = statuses.iter().filter(|s| **s == FileStatus::Missing).count();
+
+
+    // Register the removals before the downloads, so a file of the previous version is out of the way before a directory
+    // is created at its path (and the other way around).
+    let previous_state = load_local_state(&output_dir)?;
+    let is_new_version = previous_state.as_ref().is_none_or(|s| s.version_name != version_name);
+    let n_removed = match &previous_state {
+        Some(previous_state) => remove_obsolete_files(previous_state, &version_def, &output_dir, &mut transaction),
+        None => 0,
+    };
+
+    // Files of which only the permissions or modification time differ are copied to the staging area and fixed there, so
+    // they are replaced along with the downloaded files without downloading them.
+    let metadata_changes: Vec<&FileDefinition> = version_def.files.iter().zip(&statuses).filter(|(_, s)| **s == FileStatus::MetadataChanged).map(|(f, _)| f).collect();
+    for file in &metadata_changes {
+        let local_path = Path::new(&output_dir).join(&file.r_path);
+        let staged_path = transaction.stage(&file.r_path);
+        fs::copy(&local_path, &staged_path).with_whatever_context(|_| format!("Could not copy {:#?} to {:#?}", local_path, staged_path))?;
+        apply_file_metadata(file, &staged_path)?;
+    }
+
+    // Download changed and missing files to the staging area, up to `jobs` files at once. Changed files are patched if
+    // there is a patch from their current contents, and chunked files reuse the chunks of their current contents.
+    let downloads: Vec<(&FileDefinition, bool, Option<&PatchDefinition>, PathBuf)> = version_def.files.iter()
+        .zip(statuses)
+        .filter_map(|(file, status)| match status {
+            FileStatus::Unchanged | FileStatus::MetadataChanged => None,
+            FileStatus::Changed(Some(local_sha256)) => Some((file, true, file.patches.iter().find(|p| p.from_u_sha256 == local_sha256))),
+            FileStatus::Changed(None) => Some((file, true, None)),
+            FileStatus::Missing => Some((file, false, None)),
+        })
+        .map(|(file, exists, patch)| (file, exists, patch, transaction.stage(&file.r_path)))
+        .collect();
+
+    pb.set_message(format!("Downloading {} files", downloads.len()));
+    let patched: Vec<bool> = stream::iter(downloads)
+        .map(|(file, exists, patch, staged_path)| {
+            let transfer_pb = multi_progress.insert_before(&pb, ProgressBar::new(patch.map_or(file.c_len, |p| p.p_len)));
+            transfer_pb.set_style(cli::TRANSFER_STYLE.clone());
+            transfer_pb.set_message(file.r_path.clone());
+
+            let cache = cache.as_ref();
+            let storage_client = &storage_client;
+            let storage_base_path = &storage_base_path;
+            let output_dir = &output_dir;
+            let multi_progress = &multi_progress;
+            let pb = &pb;
+            async move {
+                let local_path = Path::new(output_dir).join(&file.r_path);
+                let mut patched = false;
+                if let Some(patch) = patch {
+                    match apply_patch(file, patch, &local_path, &staged_path, storage_client, storage_base_path, &transfer_pb).await {
+                        Ok(_) => patched = true,
+                        Err(e) => {
+                            multi_progress.suspend(|| println!("{}Could not patch {}, downloading the whole file instead: {e}", cli::WARNING, file.r_path));
+                            transfer_pb.reset();
+                            transfer_pb.set_length(file.c_len);
+                        },
                     }
                 }
+
+                if file.c_algo == CHUNKED_ALGO {
+                    download_chunked_file(file, exists.then_some(local_path), staged_path.clone(), storage_client, storage_base_path, &transfer_pb).await?;
+                } else if !patched {
+                    download_file(file, staged_path.clone(), cache, storage_client, storage_base_path, &transfer_pb).await?;
+                }
+                apply_file_metadata(file, &staged_path)?;
+                transfer_pb.finish_and_clear();
+                pb.inc(1);
+                Ok::<_, Whatever>(patched)
             }
+        })
+        .buffer_unordered(options.jobs)
+        .try_collect()
+        .await?;
+    let n_patched = patched.iter().filter(|p| **p).count();
+
+    pb.finish_and_clear();
+
+    println!("{} {}Applying changes...", style("[3/3]").bold().dim(), cli::CHECKLIST);
+
+    let state_r_path = Path::new(STATE_DIR_NAME).join(STATE_FILE_NAME);
+    let state_r_path = state_r_path.to_str().with_whatever_context(|| format!("Could not convert path {:#?} to string", state_r_path))?;
+    let mut channel_sequences = previous_state.as_ref().map(|s| s.channel_sequences.clone()).unwrap_or_default();
+    if let Some(channel_sequence) = resolved.channel_sequence {
+        channel_sequences.insert(name.clone(), channel_sequence);
+    }
+    save_local_state(&transaction.stage(state_r_path), &LocalState {
+        version_name: version_name.clone(),
+        files: version_def.files.iter().map(|f| f.r_path.clone()).collect(),
+        directories: version_def.directories.clone(),
+        channel_sequences,
+    })?;
+
+    transaction.commit().with_whatever_context(|_| "Could not apply changes, folder has been restored to its previous state")?;
+
+    if let Some(previous_state) = &previous_state {
+        remove_emptied_directories(previous_state, &version_def, &output_dir);
+    }
+    create_directories(&version_def, &output_dir)?;
+
+    if let Some(cache) = &cache && let Err(e) = cache.prune(options.cache_max_size) {
+        println!("{}Could not prune the download cache: {e}", cli::WARNING);
+    }
+
+    println!("\n{}Successfully finished with {} unchanged, {} changed ({} patched, {} metadata only), {} missing and {} removed files.", cli::CHECKMARK, n_unchanged, n_changed, n_patched, metadata_changes.len(), n_missing, n_removed);
+
+    // Release notes are only of interest when actually moving to the version.
+    if is_new_version && let Some(release_notes) = &version_def.release_notes {
+        println!("\n{}", style(format!("Release notes of {}:", version_def.display_version.as_deref().unwrap_or(&version_name))).bold());
+        println!("{}", release_notes.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Registers the removal of the files that were installed as part of the previous version, but are not part of the new version.
+fn remove_obsolete_files(previous_state: &LocalState, version_def: &VersionDefinition, output_dir: &str, transaction: &mut Transaction) -> u32 {
+    let obsolete_files = find_obsolete_files(previous_state, version_def, output_dir);
+    for r_path in &obsolete_files {
+        transaction.remove(r_path);
+    }
+
+    obsolete_files.len() as u32
+}
+
+/// Finds the files that were installed as part of the previous version, but are not part of the new version.
+///
+/// Only files listed in the previous local state are considered, so files that were not installed by the updater are never touched.
+pub fn find_obsolete_files<'a>(previous_state: &'a LocalState, version_def: &VersionDefinition, output_dir: &str) -> Vec<&'a str> {
+    let new_files: HashSet<&str> = version_def.files.iter().map(|f| f.r_path.as_str()).collect();
+
+    previous_state.files.iter()
+        .map(String::as_str)
+        .filter(|r_path| !new_files.contains(r_path) && Path::new(output_dir).join(r_path).is_file())
+        .collect()
+}
+
+/// Removes the directories that became empty because the files and directories the previous version installed in them
+/// are not part of the new version. Directories that still contain anything, like files that were not installed by the
+/// updater, are kept.
+fn remove_emptied_directories(previous_state: &LocalState, version_def: &VersionDefinition, output_dir: &str) {
+    let new_paths: HashSet<&str> = version_def.files.iter().map(|f| f.r_path.as_str()).chain(version_def.directories.iter().map(String::as_str)).collect();
+
+    let mut candidates = HashSet::new();
+    for r_path in previous_state.files.iter().chain(&previous_state.directories).filter(|r_path| !new_paths.contains(r_path.as_str())) {
+        // The path itself is only a directory for previous directories, removing it fails for files.
+        candidates.extend(Path::new(r_path).ancestors().filter(|p| !p.as_os_str().is_empty()));
+    }
+
+    // Remove the deepest directories first, so their parents can become empty.
+    let mut candidates: Vec<&Path> = candidates.into_iter().collect();
+    candidates.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+    for r_path in candidates {
+        let full_path = Path::new(output_dir).join(r_path);
+        if full_path.is_dir() && fs::read_dir(&full_path).is_ok_and(|mut entries| entries.next().is_none()) {
+            // A directory that can't be removed is merely left behind.
+            let _ = fs::remove_dir(&full_path);
         }
+    }
+}
 
-        pb.inc(1);
+/// Creates the empty directories of a version.
+fn create_directories(version_def: &VersionDefinition, output_dir: &str) -> Result<(), Whatever> {
+    for r_path in &version_def.directories {
+        let full_path = Path::new(output_dir).join(r_path);
+        fs::create_dir_all(&full_path).with_whatever_context(|_| format!("Could not create directory {:#?}", full_path))?;
     }
 
-    pb.finish_and_clear();
-    println!("\n{}Successfully finished with {} unchanged, {} changed and {} missing files.", cli::CHECKMARK, n_unchanged, n_changed, n_missing);
+    Ok(())
+}
+
+/// Refuses a channel that is older than the one the folder was last switched with, so an old channel (and its signature)
+/// that is put back can't roll the folder back to an old version.
+fn check_channel_sequence(previous_state: Option<&LocalState>, channel_name: &str, channel_sequence: u64) -> Result<(), Whatever> {
+    if let Some(&last_sequence) = previous_state.and_then(|s| s.channel_sequences.get(channel_name)) && channel_sequence < last_sequence {
+        whatever!("Channel {channel_name} is older than the one this folder was switched with before (sequence number {channel_sequence} instead of at least {last_sequence}), refusing to roll back");
+    }
+
+    Ok(())
+}
+
+pub fn load_local_state(output_dir: &str) -> Result<Option<LocalState>, Whatever> {
+    let state_path = Path::new(output_dir).join(STATE_DIR_NAME).join(STATE_FILE_NAME);
+
+    match File::open(&state_path) {
+        Ok(file) => Ok(Some(serde_yml::from_reader(file).with_whatever_context(|_| format!("Could not read {:#?}", state_path))?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => whatever!("Could not open {:#?}: {e}", state_path),
+    }
+}
+
+fn save_local_state(state_path: &Path, state: &LocalState) -> Result<(), Whatever> {
+    let file = File::create(state_path).with_whatever_context(|_| format!("Could not create file {:#?}", state_path))?;
+    serde_yml::to_writer(file, state).with_whatever_context(|_| format!("State file {:#?} created, but could not be written to", state_path))?;
+    Ok(())
+}
+
+#[derive(PartialEq)]
+pub enum FileStatus {
+    Unchanged,
+    /// The contents of the file are unchanged, but its permissions or modification time differ.
+    MetadataChanged,
+    /// The file differs, with its SHA256 hash if it was needed to find out or could be used to pick a patch.
+    Changed(Option<String>),
+    Missing,
+}
+
+/// Compares the local files to the files of a version, hashing up to `jobs` files at once. `pb` is advanced for every
+/// file that doesn't need to be downloaded.
+pub async fn get_file_statuses(version_def: &VersionDefinition, output_dir: &str, jobs: usize, pb: &ProgressBar) -> Result<Vec<FileStatus>, Whatever> {
+    stream::iter(&version_def.files)
+        .map(|file| async {
+            let status = get_file_status(file, output_dir).await?;
+            if matches!(status, FileStatus::Unchanged | FileStatus::MetadataChanged) {
+                pb.inc(1);
+            }
+            Ok::<_, Whatever>(status)
+        })
+        .buffered(jobs)
+        .try_collect()
+        .await
+}
+
+async fn get_file_status(file: &FileDefinition, output_dir: &str) -> Result<FileStatus, Whatever> {
+    let full_path = Path::new(output_dir).join(&file.r_path);
+    // A directory at the path of the file (or a file at the path of one of its parents) is replaced when committing.
+    let existing_file = match fs::metadata(&full_path) {
+        Ok(f) if f.is_dir() => return Ok(FileStatus::Missing),
+        Ok(f) if !f.is_file() => whatever!("Expected {:#?} to be a file", full_path),
+        Ok(f) => f,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => return Ok(FileStatus::Missing),
+        Err(e) => whatever!("Could not get metadata of file {:#?}: {e}", full_path),
+    };
+
+    // Destination file exists, first check file size (which is cheap to check). Only hash it if a patch might apply.
+    if existing_file.len() != file.u_len && file.patches.is_empty() {
+        return Ok(FileStatus::Changed(None));
+    }
+
+    // Then check the contents.
+    let sha256 = tokio::task::spawn_blocking({
+        let full_path = full_path.clone();
+        move || sha256::try_digest(full_path)
+    }).await.with_whatever_context(|_| format!("Could not hash file {:#?}", full_path))?.with_whatever_context(|_| format!("Could not get SHA256 hash for file {:#?}", full_path))?;
+
+    match sha256 == file.u_sha256 {
+        true if !metadata_matches(file, &existing_file) => Ok(FileStatus::MetadataChanged),
+        true => Ok(FileStatus::Unchanged),
+        false => Ok(FileStatus::Changed(Some(sha256))),
+    }
+}
+
+/// Gets the permission bits of a file as recorded in version definitions, `None` on platforms without Unix permissions.
+/// Special bits (like setuid) are left out, so they are never set on downloaded files.
+pub fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o777)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Whether the permissions and modification time of a local file match its definition, as far as they are recorded.
+/// Modification times are compared in whole seconds, as not every file system stores them more precisely.
+pub fn metadata_matches(file: &FileDefinition, metadata: &fs::Metadata) -> bool {
+    let mode_matches = file.mode.is_none_or(|mode| file_mode(metadata).is_none_or(|local_mode| local_mode == mode));
+    let mtime_matches = file.mtime.is_none_or(|mtime| metadata.modified().is_ok_and(|modified| DateTime::<Utc>::from(modified).timestamp() == mtime.timestamp()));
+
+    mode_matches && mtime_matches
+}
+
+/// Sets the modification time and permissions of a file to the ones recorded in its definition, if any.
+fn apply_file_metadata(file: &FileDefinition, path: &Path) -> Result<(), Whatever> {
+    // The modification time is set by path, as the file may not be writable (e.g. after a previous switch set its permissions).
+    if let Some(mtime) = file.mtime {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime.into())).with_whatever_context(|_| format!("Could not set modification time of {:#?}", path))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = file.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)).with_whatever_context(|_| format!("Could not set permissions of {:#?}", path))?;
+    }
 
     Ok(())
 }
 
-async fn get_version(stor_client: &impl FileStore, storage_base_path: &str, version_name: &str) -> Result<VersionDefinition, Whatever> {
+/// Returns the first compression algorithm used by a file (or its chunks) that is not supported, if any.
+fn find_unsupported_algo(file: &FileDefinition) -> Option<&str> {
+    if file.c_algo == CHUNKED_ALGO {
+        return file.chunks.iter().map(|c| c.c_algo.as_str()).find(|c_algo| Codec::from_name(c_algo).is_none());
+    }
+
+    Codec::from_name(&file.c_algo).is_none().then_some(file.c_algo.as_str())
+}
+
+pub async fn get_version(stor_client: &impl FileStore, storage_base_path: &str, version_name: &str) -> Result<VersionDefinition, Whatever> {
+    let version_bytes = fetch_version(stor_client, storage_base_path, version_name).await?;
+    parse_version(&version_bytes, version_name)
+}
+
+/// Gets a version definition to switch to, checking its signature and that all of its files can be decompressed.
+pub async fn get_switchable_version(stor_client: &impl FileStore, storage_base_path: &str, version_name: &str, options: &SwitchOptions) -> Result<VersionDefinition, Whatever> {
+    let version_def = get_verified_version(stor_client, storage_base_path, version_name, options).await.with_whatever_context(|_| format!("Could not get info of version {version_name}"))?;
+    if let Some(min_updater_version) = &version_def.min_updater_version {
+        check_min_updater_version(min_updater_version, version_name)?;
+    }
+    for file in &version_def.files {
+        if let Some(c_algo) = find_unsupported_algo(file) {
+            whatever!("File {} of version {version_name} uses compression algorithm {c_algo}, which is not supported by this version of h3xup", file.r_path);
+        }
+    }
+
+    Ok(version_def)
+}
+
+/// Refuses to switch to a version that requires a newer version of h3xup to be applied correctly.
+fn check_min_updater_version(min_updater_version: &str, version_name: &str) -> Result<(), Whatever> {
+    let required = semver::Version::parse(min_updater_version).with_whatever_context(|_| format!("Version {version_name} requires h3xup {min_updater_version}, which is not a valid version"))?;
+    let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).with_whatever_context(|_| "Could not parse the version of h3xup")?;
+    if current < required {
+        whatever!("Version {version_name} requires h3xup {required} or newer, but this is h3xup {current}");
+    }
+
+    Ok(())
+}
+
+/// Gets a version definition and checks its signature against the trusted keys, unless unsigned versions are allowed.
+async fn get_verified_version(stor_client: &impl FileStore, storage_base_path: &str, version_name: &str, options: &SwitchOptions) -> Result<VersionDefinition, Whatever> {
+    let version_bytes = fetch_version(stor_client, storage_base_path, version_name).await?;
+
+    if !options.allow_unsigned {
+        let signature_path = signing::signature_path(storage_base_path, version_name);
+        check_signature(stor_client, &signature_path, signing::VERSION_KIND, version_name, &version_bytes, &options.trusted_keys).await?;
+    }
+
+    parse_version(&version_bytes, version_name)
+}
+
+/// Checks the signature (stored at `signature_path`) of a signed object, like a version definition, against the trusted
+/// keys. The signature covers the name, so a signed object copied from another name is rejected.
+pub async fn check_signature(stor_client: &impl FileStore, signature_path: &Path, kind: &str, name: &str, contents: &[u8], trusted_keys: &[String]) -> Result<(), Whatever> {
+    if trusted_keys.is_empty() {
+        whatever!("No trusted public keys configured to check the signature of {kind} {name} with, pass one using --trusted-key or allow unsigned versions using --allow-unsigned");
+    }
+    let trusted_keys = trusted_keys.iter().map(|k| signing::parse_public_key(k)).collect::<Result<Vec<_>, _>>()?;
+
+    let mut title = kind.to_owned();
+    title[..1].make_ascii_uppercase();
+
+    let signature_file = stor_client.get_file(signature_path).await.with_whatever_context(|_| format!("Could not get file info for {:#?}", signature_path))?;
+    let Some(signature_file) = signature_file else {
+        whatever!("{title} {name} is not signed");
+    };
+    let signature = signature_file.stream.try_collect::<Vec<Bytes>>().await.with_whatever_context(|_| format!("Could not download {:#?}", signature_path))?.concat();
+
+    let document = signing::signed_document(kind, name, contents);
+    if !signing::verify(&trusted_keys, &document, &String::from_utf8_lossy(&signature)) {
+        whatever!("{title} {name} is not signed by any of the trusted keys (or its signature belongs to another {kind})");
+    }
+
+    Ok(())
+}
+
+/// Downloads the raw version definition, which is what its signature is made over (together with its name).
+async fn fetch_version(stor_client: &impl FileStore, storage_base_path: &str, version_name: &str) -> Result<Vec<u8>, Whatever> {
     let version_storage_path = Path::new(storage_base_path).join("versions").join(version_name);
     let fetched_version_file = stor_client.get_file(version_storage_path.as_path()).await.with_whatever_context(|_| format!("Could not get file info for {:#?}", version_storage_path))?.with_whatever_context(|| format!("Could not find file {:#?}", version_storage_path))?;
-    let fetched_version_file_chunks = fetched_version_file.stream.collect::<Vec<bytes::Bytes>>().await;
-    let fetched_version_file_bytes = fetched_version_file_chunks.iter().flat_map(|b| b.as_ref()).cloned().collect::<Vec<u8>>();
+    let fetched_version_file_chunks = fetched_version_file.stream.try_collect::<Vec<bytes::Bytes>>().await.with_whatever_context(|_| format!("Could not download {:#?}", version_storage_path))?;
+
+    Ok(fetched_version_file_chunks.concat())
+}
+
+fn parse_version(version_bytes: &[u8], version_name: &str) -> Result<VersionDefinition, Whatever> {
+    let version_yaml = String::from_utf8_lossy(version_bytes);
+    let header: DefinitionHeader = serde_yml::from_str(&version_yaml).with_whatever_context(|_| "Could not parse version YAML")?;
+    if DefinitionVersion::try_from(header.version).is_err() {
+        whatever!("Version {version_name} uses definition schema version {}, which is not supported by this version of h3xup", header.version);
+    }
 
-    let version_yaml = String::from_utf8_lossy(&fetched_version_file_bytes);
     serde_yml::from_str(&version_yaml).with_whatever_context(|_| "Could not parse version YAML")
 }
 
-async fn download_file(file: &FileDefinition, full_path: PathBuf, storage_client: &impl FileStore, upload_base_path: &str) -> Result<(), Whatever> {
+/// Downloads and decompresses a file, downloading it again if it doesn't pass its integrity check and resuming the
+/// download if it is incomplete.
+///
+/// The compressed file is kept in the blob cache (if any), so a switch that was interrupted resumes the download where
+/// it stopped and switching back to the version later doesn't download the file again.
+async fn download_file(file: &FileDefinition, full_path: PathBuf, cache: Option<&BlobCache>, storage_client: &impl FileStore, upload_base_path: &str, pb: &ProgressBar) -> Result<(), Whatever> {
+    let cache_entry = cache.and_then(|cache| cache.claim(&file.u_sha256, &file.c_algo));
+
+    let mut attempt = 1;
+    loop {
+        match try_download_file(file, &full_path, cache_entry.as_ref(), storage_client, upload_base_path, pb).await? {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::InvalidData && attempt < MAX_DOWNLOAD_ATTEMPTS => {
+                if let Some(cache_entry) = &cache_entry {
+                    cache_entry.discard().with_whatever_context(|_| format!("Could not remove cached file {:#?}", cache_entry.blob_path()))?;
+                }
+                pb.suspend(|| println!("{}File {} failed its integrity check ({e}), downloading it again...", cli::WARNING, file.r_path));
+                pb.reset();
+                attempt += 1;
+            },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && attempt < MAX_DOWNLOAD_ATTEMPTS => {
+                pb.suspend(|| println!("{}Download of file {} is incomplete ({e}), resuming it...", cli::WARNING, file.r_path));
+                pb.reset();
+                attempt += 1;
+            },
+            Err(e) if e.kind() == ErrorKind::InvalidData => whatever!("File {} failed its integrity check {attempt} times: {e}", file.r_path),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => whatever!("Download of file {} was incomplete {attempt} times: {e}", file.r_path),
+            Err(e) => return Err(e).with_whatever_context(|_| format!("Could not decompress to {:#?}", full_path)),
+        }
+    }
+}
+
+/// Downloads and decompresses a file once, continuing with what is in the cache. Integrity check failures are returned as
+/// I/O errors of kind [`ErrorKind::InvalidData`] and incomplete downloads as [`ErrorKind::UnexpectedEof`], so they can be
+/// retried.
+async fn try_download_file(file: &FileDefinition, full_path: &Path, cache_entry: Option<&CacheEntry<'_>>, storage_client: &impl FileStore, upload_base_path: &str, pb: &ProgressBar) -> Result<io::Result<()>, Whatever> {
     let download_path = Path::new(upload_base_path).join("files").join(&file.u_sha256);
 
-    let file = storage_client.get_file(download_path.as_path()).await
-        .with_whatever_context(|_| format!("Could not get file info for {:#?}", download_path))?.with_whatever_context(|| format!("Could not find file {:#?}", download_path))?;
+    let codec = Codec::from_name(&file.c_algo).with_whatever_context(|| format!("Compression algorithm {} of file {:#?} is not supported", file.c_algo, download_path))?;
+    let mut compressed_checker = Checker::new("Compressed data", file.c_len, file.c_sha256.as_deref());
+    let decompressed_checker = Checker::new("Decompressed data", file.u_len, Some(&file.u_sha256));
+
+    // Use the cached file if it was downloaded completely, otherwise continue the partial download (if any).
+    let (cached_path, cached_len, part_path) = match cache_entry {
+        Some(cache_entry) => {
+            let blob_len = cache_entry.blob_len().with_whatever_context(|_| format!("Could not get size of cached file {:#?}", cache_entry.blob_path()))?;
+            let part_len = cache_entry.part_len().with_whatever_context(|_| format!("Could not get size of cached file {:#?}", cache_entry.part_path()))?;
+            match (blob_len, part_len) {
+                (Some(blob_len), _) if blob_len == file.c_len => (Some(cache_entry.blob_path()), blob_len, None),
+                (None, Some(part_len)) if part_len <= file.c_len => (Some(cache_entry.part_path()), part_len, Some(cache_entry.part_path())),
+                (None, None) => (None, 0, Some(cache_entry.part_path())),
+                _ => {
+                    cache_entry.discard().with_whatever_context(|_| format!("Could not remove cached file {:#?}", cache_entry.blob_path()))?;
+                    (None, 0, Some(cache_entry.part_path()))
+                },
+            }
+        },
+        None => (None, 0, None),
+    };
+    let completes_part = part_path.is_some();
 
-    // Collect the stream into a single buffer
-    let bytes_vec = file.stream.collect::<Vec<bytes::Bytes>>().await;
-    let data = bytes_vec.iter().flat_map(|b| b.as_ref()).cloned().collect::<Vec<u8>>();
+    let stream = if cached_len < file.c_len {
+        let remote_file = storage_client.get_file_from(download_path.as_path(), cached_len).await
+            .with_whatever_context(|_| format!("Could not get file info for {:#?}", download_path))?.with_whatever_context(|| format!("Could not find file {:#?}", download_path))?;
+        remote_file.stream
+    } else {
+        Box::pin(stream::empty())
+    };
 
-    let mut reader = Cursor::new(data);
     if let Some(parent) = full_path.parent() {
         fs::create_dir_all(parent).with_whatever_context(|_| format!("Could not create directory {:#?}", parent))?;
     }
-    let mut local_file = File::create(&full_path).with_whatever_context(|_| format!("Could not create file {:#?}", full_path))?;
-    brotli::BrotliDecompress(&mut reader, &mut local_file).with_whatever_context(|_| format!("Could not decompress to {:#?}", full_path))?;
+    let local_file = File::create(full_path).with_whatever_context(|_| format!("Could not create file {:#?}", full_path))?;
+
+    pb.inc(cached_len);
+    let result = consume_blocking(stream, pb, move |mut receiver| {
+        let mut writer = codec.decompressor(CheckedWriter::new(BufWriter::new(local_file), decompressed_checker))?;
+        let mut part_file = part_path.map(|path| File::options().create(true).append(true).open(path)).transpose()?;
+
+        // Keep checking the compressed data if decompressing fails, so corrupt data is reported as such.
+        let mut decompress_result = Ok(());
+        let mut process = |chunk: &[u8]| {
+            compressed_checker.update(chunk)?;
+            if decompress_result.is_ok() {
+                decompress_result = writer.write_all(chunk);
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        if let Some(cached_path) = cached_path {
+            let mut cached_file = File::open(cached_path)?;
+            let mut buffer = vec![0; CACHE_READ_BUFFER_SIZE];
+            loop {
+                match cached_file.read(&mut buffer)? {
+                    0 => break,
+                    n => process(&buffer[..n])?,
+                }
+            }
+        }
+        while let Some(chunk) = receiver.blocking_recv() {
+            if let Some(part_file) = &mut part_file {
+                part_file.write_all(&chunk)?;
+            }
+            process(&chunk)?;
+        }
+        compressed_checker.finish()?;
+
+        // The compressed data is complete, so decompressed data that is incomplete means the compressed data is corrupt.
+        let corrupt = |e: io::Error| if e.kind() == ErrorKind::UnexpectedEof { io::Error::new(ErrorKind::InvalidData, e) } else { e };
+        decompress_result.map_err(corrupt)?;
+        writer.finish().and_then(CheckedWriter::finish).map_err(corrupt)?.flush()
+    }).await.with_whatever_context(|_| format!("Could not download {:#?}", download_path))?;
+
+    if let (Ok(_), Some(cache_entry)) = (&result, cache_entry) {
+        if completes_part {
+            cache_entry.complete().with_whatever_context(|_| format!("Could not complete cached file {:#?}", cache_entry.blob_path()))?;
+        } else {
+            cache_entry.touch().with_whatever_context(|_| format!("Could not update cached file {:#?}", cache_entry.blob_path()))?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// A chunk of a file that is being assembled.
+enum ChunkSource {
+    /// A chunk of the current local file.
+    Local { offset: u64, len: u64, u_sha256: String },
+
+    /// A downloaded chunk that passed its integrity check.
+    Remote { codec: Codec, data: Vec<u8>, u_len: u64, u_sha256: String },
+}
+
+/// Assembles a chunked file, reusing the chunks of the current local file (if any) and downloading the missing ones.
+async fn download_chunked_file(file: &FileDefinition, local_path: Option<PathBuf>, full_path: PathBuf, storage_client: &impl FileStore, upload_base_path: &str, pb: &ProgressBar) -> Result<(), Whatever> {
+    let local_chunks = chunk_local_file(local_path.as_deref()).await?;
+    let local_chunks: HashMap<&str, &LocalChunk> = local_chunks.iter().map(|c| (c.u_sha256.as_str(), c)).collect();
+    pb.set_length(file.chunks.iter().filter(|c| !local_chunks.contains_key(c.u_sha256.as_str())).map(|c| c.c_len).sum());
+
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent).with_whatever_context(|_| format!("Could not create directory {:#?}", parent))?;
+    }
+    let new_file = File::create(&full_path).with_whatever_context(|_| format!("Could not create file {:#?}", full_path))?;
+
+    // Write the chunks on the blocking pool while downloading, as reading, decompressing and hashing them is CPU bound.
+    let (sender, mut receiver) = mpsc::channel::<ChunkSource>(CHUNK_QUEUE_LEN);
+    let assembled_checker = Checker::new("Assembled file", file.u_len, Some(&file.u_sha256));
+    let assembler = tokio::task::spawn_blocking(move || {
+        let mut local_file = local_path.map(File::open).transpose()?;
+        let mut writer = CheckedWriter::new(BufWriter::new(new_file), assembled_checker);
+        while let Some(source) = receiver.blocking_recv() {
+            let data = match source {
+                ChunkSource::Local { offset, len, u_sha256 } => {
+                    let local_file = local_file.as_mut().ok_or_else(|| io::Error::other("Local chunk without local file"))?;
+                    let data = chunking::read_chunk(local_file, offset, len)?;
+                    integrity::check("Local chunk", &data, len, &u_sha256)?;
+                    data
+                },
+                ChunkSource::Remote { codec, data, u_len, u_sha256 } => {
+                    let mut decompressor = codec.decompressor(CheckedWriter::new(Vec::new(), Checker::new("Decompressed chunk", u_len, Some(&u_sha256))))?;
+                    decompressor.write_all(&data)?;
+                    decompressor.finish()?.finish()?
+                },
+            };
+            writer.write_all(&data)?;
+        }
+
+        writer.finish()?.flush()
+    });
+
+    for chunk in &file.chunks {
+        let source = match local_chunks.get(chunk.u_sha256.as_str()) {
+            Some(local_chunk) => ChunkSource::Local { offset: local_chunk.offset, len: local_chunk.len, u_sha256: chunk.u_sha256.clone() },
+            None => {
+                let codec = Codec::from_name(&chunk.c_algo).with_whatever_context(|| format!("Compression algorithm {} of chunk {} is not supported", chunk.c_algo, chunk.u_sha256))?;
+                let data = download_chunk(chunk, storage_client, upload_base_path, pb).await?;
+                ChunkSource::Remote { codec, data, u_len: chunk.u_len, u_sha256: chunk.u_sha256.clone() }
+            },
+        };
+
+        if sender.send(source).await.is_err() {
+            // The assembler stopped early, its error is reported below.
+            break;
+        }
+    }
+    drop(sender);
+
+    assembler.await.with_whatever_context(|_| format!("Could not assemble {:#?}", full_path))?.with_whatever_context(|_| format!("Could not assemble {:#?}", full_path))?;
 
     Ok(())
 }
+
+/// Chunks the local file (if any) the same way it was chunked when creating the version, to find out which chunks it still has.
+pub async fn chunk_local_file(local_path: Option<&Path>) -> Result<Vec<LocalChunk>, Whatever> {
+    let Some(local_path) = local_path else {
+        return Ok(Vec::new());
+    };
+
+    tokio::task::spawn_blocking({
+        let local_path = local_path.to_owned();
+        move || chunking::chunk_file(&local_path)
+    }).await
+        .with_whatever_context(|_| format!("Could not chunk file {:#?}", local_path))?
+        .with_whatever_context(|_| format!("Could not chunk file {:#?}", local_path))
+}
+
+/// Downloads a compressed chunk into memory, downloading it again if it doesn't pass its integrity check.
+async fn download_chunk(chunk: &ChunkDefinition, storage_client: &impl FileStore, upload_base_path: &str, pb: &ProgressBar) -> Result<Vec<u8>, Whatever> {
+    let download_path = chunk_path(upload_base_path, &chunk.u_sha256);
+
+    let mut attempt = 1;
+    loop {
+        let remote_chunk = storage_client.get_file(download_path.as_path()).await
+            .with_whatever_context(|_| format!("Could not get file info for {:#?}", download_path))?.with_whatever_context(|| format!("Could not find file {:#?}", download_path))?;
+        let data = remote_chunk.stream.inspect_ok(|part| pb.inc(part.len() as u64)).try_collect::<Vec<Bytes>>().await
+            .with_whatever_context(|_| format!("Could not download {:#?}", download_path))?.concat();
+
+        match integrity::check("Compressed chunk", &data, chunk.c_len, &chunk.c_sha256) {
+            Ok(_) => return Ok(data),
+            Err(e) if attempt < MAX_DOWNLOAD_ATTEMPTS => {
+                pb.suspend(|| println!("{}Chunk {} failed its integrity check ({e}), downloading it again...", cli::WARNING, chunk.u_sha256));
+                pb.set_position(pb.position().saturating_sub(data.len() as u64));
+                attempt += 1;
+            },
+            Err(e) => whatever!("Chunk {} failed its integrity check {attempt} times: {e}", chunk.u_sha256),
+        }
+    }
+}
+
+/// Downloads a patch and applies it to the local file, writing the result to `staged_path`.
+///
+/// The local file is read into memory as a whole, as the patch may refer to any part of it.
+async fn apply_patch(file: &FileDefinition, patch: &PatchDefinition, local_path: &Path, staged_path: &Path, storage_client: &impl FileStore, upload_base_path: &str, pb: &ProgressBar) -> Result<(), Whatever> {
+    if patch.p_algo != PATCH_ALGO {
+        whatever!("Patch algorithm {} is not supported", patch.p_algo);
+    }
+
+    let download_path = patch_path(upload_base_path, &patch.from_u_sha256, &file.u_sha256);
+    let patch_file = storage_client.get_file(download_path.as_path()).await
+        .with_whatever_context(|_| format!("Could not get file info for {:#?}", download_path))?.with_whatever_context(|| format!("Could not find file {:#?}", download_path))?;
+
+    let old_data = tokio::fs::read(local_path).await.with_whatever_context(|_| format!("Could not read file {:#?}", local_path))?;
+    let local_file = File::create(staged_path).with_whatever_context(|_| format!("Could not create file {:#?}", staged_path))?;
+
+    let mut patch_checker = Checker::new("Patch", patch.p_len, Some(&patch.p_sha256));
+    let patched_checker = Checker::new("Patched file", file.u_len, Some(&file.u_sha256));
+    consume_blocking(patch_file.stream, pb, move |mut receiver| {
+        let mut writer = delta::patch_writer(&old_data, CheckedWriter::new(BufWriter::new(local_file), patched_checker))?;
+
+        // Keep checking the patch if applying it fails, so a corrupt patch is reported as such.
+        let mut patch_result = Ok(());
+        while let Some(chunk) = receiver.blocking_recv() {
+            patch_checker.update(&chunk)?;
+            if patch_result.is_ok() {
+                patch_result = writer.write_all(&chunk);
+            }
+        }
+        patch_checker.finish()?;
+        patch_result?;
+        writer.flush()?;
+
+        // An incomplete patch or one applied to the wrong file results in a different file, which the checker reports.
+        writer.into_inner().finish()?.flush()
+    }).await.with_whatever_context(|_| format!("Could not apply patch {:#?}", download_path))?.with_whatever_context(|_| format!("Could not apply patch to {:#?}", local_path))?;
+
+    Ok(())
+}
+
+/// Feeds the chunks of a downloaded stream to `consume` on the blocking pool while downloading, so files are never kept in
+/// memory as a whole and CPU bound work doesn't hold up other downloads.
+///
+/// A download that fails midway is returned as an I/O error of kind [`ErrorKind::UnexpectedEof`], so it can be resumed,
+/// unless `consume` already found the data received until then to be corrupt. The result of `consume` is returned otherwise.
+async fn consume_blocking<F>(mut stream: BoxStream<'static, io::Result<Bytes>>, pb: &ProgressBar, consume: F) -> Result<io::Result<()>, Whatever>
+where
+    F: FnOnce(mpsc::Receiver<Bytes>) -> io::Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel::<Bytes>(DECOMPRESS_QUEUE_LEN);
+    let consumer = tokio::task::spawn_blocking(move || consume(receiver));
+
+    let mut download_result = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                download_result = Err(e);
+                break;
+            },
+        };
+
+        pb.inc(chunk.len() as u64);
+        if sender.send(chunk).await.is_err() {
+            // The consumer stopped early, its error is reported by the caller.
+            break;
+        }
+    }
+    drop(sender);
+
+    let consume_result = consumer.await.with_whatever_context(|_| "Could not process download")?;
+    // Report a failed download itself rather than the incomplete data it results in.
+    if let Err(e) = download_result && !consume_result.as_ref().is_err_and(|e| e.kind() == ErrorKind::InvalidData) {
+        return Ok(Err(io::Error::new(ErrorKind::UnexpectedEof, format!("Download failed: {e}"))));
+    }
+
+    Ok(consume_result)
+}
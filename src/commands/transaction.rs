@@ -0,0 +1,380 @@
+use std::{fs::{self, File}, io::{self, ErrorKind, Write}, path::{Path, PathBuf}};
+
+use snafu::{whatever, ResultExt, Whatever};
+
+use crate::models::local_state::*;
+
+/// Suffix of the marker in the backup directory that records that an entry had no previous file.
+const ABSENT_SUFFIX: &str = ".absent";
+
+/// A set of file replacements and removals in an output directory that is applied all at once.
+///
+/// New files are first written to the staging directory. On commit, every file that is replaced or removed is moved to the
+/// backup directory, so the output directory can be restored to exactly its previous state if anything fails. A journal
+/// is written before the first file is touched and removed after the last one, so a commit that got interrupted (e.g. by a
+/// crash) is rolled back by [`recover`] on the next run.
+pub struct Transaction {
+    output_dir: PathBuf,
+    state_dir: PathBuf,
+    journal: Journal,
+}
+
+impl Transaction {
+    /// Starts a new transaction, rolling back any interrupted one first.
+    ///
+    /// Returns the transaction and whether an interrupted transaction was rolled back.
+    pub fn begin(output_dir: &str) -> Result<(Transaction, bool), Whatever> {
+        let recovered = recover(output_dir)?;
+
+        let state_dir = Path::new(output_dir).join(STATE_DIR_NAME);
+        let staging_dir = state_dir.join(STAGING_DIR_NAME);
+        fs::create_dir_all(&staging_dir).with_whatever_context(|_| format!("Could not create directory {:#?}", staging_dir))?;
+
+        Ok((Transaction { output_dir: PathBuf::from(output_dir), state_dir, journal: Journal { entries: Vec::new() } }, recovered))
+    }
+
+    /// Registers a replacement of the file at `r_path` and returns the path the new file should be staged at.
+    pub fn stage(&mut self, r_path: &str) -> PathBuf {
+        let name = self.journal.entries.len().to_string();
+        let staged_path = self.state_dir.join(STAGING_DIR_NAME).join(&name);
+        self.journal.entries.push(JournalEntry { r_path: r_path.to_owned(), staged: Some(name.clone()), backup: name });
+        staged_path
+    }
+
+    /// Registers a removal of the file at `r_path`.
+    pub fn remove(&mut self, r_path: &str) {
+        let name = self.journal.entries.len().to_string();
+        self.journal.entries.push(JournalEntry { r_path: r_path.to_owned(), staged: None, backup: name });
+    }
+
+    /// Applies all registered operations. If any of them fails, the output directory is rolled back to its previous state.
+    pub fn commit(self) -> Result<(), Whatever> {
+        let backup_dir = self.state_dir.join(BACKUP_DIR_NAME);
+        fs::create_dir_all(&backup_dir).with_whatever_context(|_| format!("Could not create directory {:#?}", backup_dir))?;
+        write_journal(&self.state_dir, &self.journal)?;
+
+        for entry in &self.journal.entries {
+            if let Err(error) = apply_entry(&self.output_dir, &self.state_dir, entry) {
+                roll_back(&self.output_dir, &self.state_dir, &self.journal).with_whatever_context(|_| format!("Could not roll back after failed commit ({error})"))?;
+                finish(&self.state_dir)?;
+                return Err(error);
+            }
+        }
+
+        finish(&self.state_dir)
+    }
+}
+
+/// Rolls back a commit that got interrupted, if any. Returns whether anything was rolled back.
+pub fn recover(output_dir: &str) -> Result<bool, Whatever> {
+    let state_dir = Path::new(output_dir).join(STATE_DIR_NAME);
+    let journal_path = state_dir.join(JOURNAL_FILE_NAME);
+
+    let journal: Journal = match File::open(&journal_path) {
+        Ok(file) => serde_yml::from_reader(file).with_whatever_context(|_| format!("Could not read {:#?}", journal_path))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            // Nothing to roll back, but there may be leftovers of a switch that never reached its commit.
+            remove_dir_if_exists(&state_dir.join(STAGING_DIR_NAME))?;
+            remove_dir_if_exists(&state_dir.join(BACKUP_DIR_NAME))?;
+            return Ok(false);
+        },
+        Err(e) => whatever!("Could not open {:#?}: {e}", journal_path),
+    };
+
+    roll_back(Path::new(output_dir), &state_dir, &journal)?;
+    finish(&state_dir)?;
+
+    Ok(true)
+}
+
+fn apply_entry(output_dir: &Path, state_dir: &Path, entry: &JournalEntry) -> Result<(), Whatever> {
+    let target_path = output_dir.join(&entry.r_path);
+    let backup_path = state_dir.join(BACKUP_DIR_NAME).join(&entry.backup);
+
+    // A directory in the way of a new file is moved to the backup like a file, but only once the files of the previous
+    // version in it have been removed, so files that were not installed by the updater are never touched.
+    if target_path.is_dir() && contains_files(&target_path).with_whatever_context(|_| format!("Could not read directory {:#?}", target_path))? {
+        whatever!("Could not replace directory {:#?} by a file, as it still contains files", target_path);
+    }
+
+    match fs::rename(&target_path, &backup_path) {
+        Ok(_) => {},
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            // Record that there was no file, so a rollback knows the file at the target is a new one.
+            let absent_path = absent_marker_path(&backup_path);
+            File::create(&absent_path).with_whatever_context(|_| format!("Could not create file {:#?}", absent_path))?;
+        },
+        Err(e) => whatever!("Could not move {:#?} to {:#?}: {e}", target_path, backup_path),
+    }
+
+    if let Some(staged) = &entry.staged {
+        let staged_path = state_dir.join(STAGING_DIR_NAME).join(staged);
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).with_whatever_context(|_| format!("Could not create directory {:#?}", parent))?;
+        }
+        fs::rename(&staged_path, &target_path).with_whatever_context(|_| format!("Could not move {:#?} to {:#?}", staged_path, target_path))?;
+    }
+
+    Ok(())
+}
+
+/// Undoes the entries of a journal in reverse order. Entries that were not (fully) applied or were already rolled back
+/// are detected by which of the staged, backup and marker files still exist, so this is safe to run on a partially
+/// applied journal and to run again after a rollback got interrupted.
+fn roll_back(output_dir: &Path, state_dir: &Path, journal: &Journal) -> Result<(), Whatever> {
+    for entry in journal.entries.iter().rev() {
+        let target_path = output_dir.join(&entry.r_path);
+        let backup_path = state_dir.join(BACKUP_DIR_NAME).join(&entry.backup);
+        let absent_path = absent_marker_path(&backup_path);
+
+        // A staged file that no longer exists has been moved into place, so the file at the target is the new one. That is,
+        // until the entry has been rolled back, which removes the backup or marker of the previous file last.
+        let staged_in_place = entry.staged.as_ref().is_some_and(|staged| !state_dir.join(STAGING_DIR_NAME).join(staged).exists());
+        let new_file_in_place = staged_in_place && (backup_path.exists() || absent_path.exists());
+        if new_file_in_place {
+            match fs::remove_file(&target_path) {
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => whatever!("Could not remove {:#?}: {e}", target_path),
+            }
+        }
+
+        if backup_path.exists() {
+            // Directories created for new files may be in the way of a file that is restored.
+            if target_path.is_dir() && !contains_files(&target_path).with_whatever_context(|_| format!("Could not read directory {:#?}", target_path))? {
+                fs::remove_dir_all(&target_path).with_whatever_context(|_| format!("Could not remove directory {:#?}", target_path))?;
+            }
+            fs::rename(&backup_path, &target_path).with_whatever_context(|_| format!("Could not restore {:#?} from {:#?}", target_path, backup_path))?;
+        }
+        match fs::remove_file(&absent_path) {
+            Ok(_) => {},
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => whatever!("Could not remove {:#?}: {e}", absent_path),
+        }
+    }
+
+    Ok(())
+}
+
+/// The path of the marker that records that there was no file to back up for an entry.
+fn absent_marker_path(backup_path: &Path) -> PathBuf {
+    let mut path = backup_path.as_os_str().to_owned();
+    path.push(ABSENT_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Whether a directory contains any files, directly or in one of its subdirectories.
+fn contains_files(dir: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || contains_files(&entry.path())? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn write_journal(state_dir: &Path, journal: &Journal) -> Result<(), Whatever> {
+    let journal_path = state_dir.join(JOURNAL_FILE_NAME);
+    let temp_path = state_dir.join(format!("{JOURNAL_FILE_NAME}.tmp"));
+
+    let yaml = serde_yml::to_string(journal).with_whatever_context(|_| "Could not convert journal to YAML")?;
+    let mut file = File::create(&temp_path).with_whatever_context(|_| format!("Could not create file {:#?}", temp_path))?;
+    file.write_all(yaml.as_bytes()).with_whatever_context(|_| format!("Could not write to {:#?}", temp_path))?;
+    file.sync_all().with_whatever_context(|_| format!("Could not flush {:#?}", temp_path))?;
+    fs::rename(&temp_path, &journal_path).with_whatever_context(|_| format!("Could not move {:#?} to {:#?}", temp_path, journal_path))?;
+
+    Ok(())
+}
+
+/// Marks a commit or rollback as finished by removing the journal, then cleans up the staging and backup directories.
+fn finish(state_dir: &Path) -> Result<(), Whatever> {
+    let journal_path = state_dir.join(JOURNAL_FILE_NAME);
+    match fs::remove_file(&journal_path) {
+        Ok(_) => {},
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => whatever!("Could not remove {:#?}: {e}", journal_path),
+    }
+
+    remove_dir_if_exists(&state_dir.join(STAGING_DIR_NAME))?;
+    remove_dir_if_exists(&state_dir.join(BACKUP_DIR_NAME))
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), Whatever> {
+    match fs::remove_dir_all(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => whatever!("Could not remove directory {:#?}: {e}", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn write(dir: &TempDir, r_path: &str, contents: &str) {
+        let path = dir.path().join(r_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn read(dir: &TempDir, r_path: &str) -> Option<String> {
+        fs::read_to_string(dir.path().join(r_path)).ok()
+    }
+
+    fn assert_cleaned_up(dir: &TempDir) {
+        let state_dir = dir.path().join(STATE_DIR_NAME);
+        assert!(!state_dir.join(JOURNAL_FILE_NAME).exists());
+        assert!(!state_dir.join(STAGING_DIR_NAME).exists());
+        assert!(!state_dir.join(BACKUP_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn commit_replaces_adds_and_removes_files() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "replaced.txt", "old");
+        write(&dir, "removed.txt", "old");
+        let (mut transaction, recovered) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        assert!(!recovered);
+
+        fs::write(transaction.stage("replaced.txt"), "new").unwrap();
+        fs::write(transaction.stage("sub/added.txt"), "new").unwrap();
+        transaction.remove("removed.txt");
+        transaction.commit().unwrap();
+
+        assert_eq!(read(&dir, "replaced.txt").as_deref(), Some("new"));
+        assert_eq!(read(&dir, "sub/added.txt").as_deref(), Some("new"));
+        assert_eq!(read(&dir, "removed.txt"), None);
+        assert_cleaned_up(&dir);
+    }
+
+    #[test]
+    fn failed_commit_rolls_back() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "replaced.txt", "old");
+        write(&dir, "removed.txt", "old");
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+
+        fs::write(transaction.stage("replaced.txt"), "new").unwrap();
+        transaction.remove("removed.txt");
+        fs::write(transaction.stage("added.txt"), "new").unwrap();
+        // Never staged, so moving it into place fails.
+        transaction.stage("missing.txt");
+        assert!(transaction.commit().is_err());
+
+        assert_eq!(read(&dir, "replaced.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "removed.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "added.txt"), None);
+        assert_cleaned_up(&dir);
+    }
+
+    #[test]
+    fn recover_rolls_back_interrupted_commit() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "first.txt", "old");
+        write(&dir, "second.txt", "old");
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        fs::write(transaction.stage("first.txt"), "new").unwrap();
+        fs::write(transaction.stage("second.txt"), "new").unwrap();
+
+        // Simulate a crash after the first entry of the commit was applied.
+        fs::create_dir_all(transaction.state_dir.join(BACKUP_DIR_NAME)).unwrap();
+        write_journal(&transaction.state_dir, &transaction.journal).unwrap();
+        apply_entry(&transaction.output_dir, &transaction.state_dir, &transaction.journal.entries[0]).unwrap();
+        assert_eq!(read(&dir, "first.txt").as_deref(), Some("new"));
+
+        assert!(recover(dir.path().to_str().unwrap()).unwrap());
+        assert_eq!(read(&dir, "first.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "second.txt").as_deref(), Some("old"));
+        assert_cleaned_up(&dir);
+        assert!(!recover(dir.path().to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn recover_can_run_again_after_interrupted_rollback() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "replaced.txt", "old");
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        fs::write(transaction.stage("replaced.txt"), "new").unwrap();
+        fs::write(transaction.stage("added.txt"), "new").unwrap();
+        fs::create_dir_all(transaction.state_dir.join(BACKUP_DIR_NAME)).unwrap();
+        write_journal(&transaction.state_dir, &transaction.journal).unwrap();
+        for entry in &transaction.journal.entries {
+            apply_entry(&transaction.output_dir, &transaction.state_dir, entry).unwrap();
+        }
+
+        // Simulate a crash after the rollback restored the files, but before the journal was removed.
+        roll_back(&transaction.output_dir, &transaction.state_dir, &transaction.journal).unwrap();
+        assert!(recover(dir.path().to_str().unwrap()).unwrap());
+        assert_eq!(read(&dir, "replaced.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "added.txt"), None);
+        assert_cleaned_up(&dir);
+    }
+
+    #[test]
+    fn recover_twice_restores_files() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "first.txt", "old");
+        write(&dir, "second.txt", "old");
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        fs::write(transaction.stage("first.txt"), "new").unwrap();
+        fs::write(transaction.stage("second.txt"), "new").unwrap();
+        fs::write(transaction.stage("third.txt"), "new").unwrap();
+        fs::create_dir_all(transaction.state_dir.join(BACKUP_DIR_NAME)).unwrap();
+        write_journal(&transaction.state_dir, &transaction.journal).unwrap();
+        for entry in &transaction.journal.entries {
+            apply_entry(&transaction.output_dir, &transaction.state_dir, entry).unwrap();
+        }
+
+        // Simulate a crash in the middle of a rollback, after the last two entries were undone.
+        let undone = Journal { entries: transaction.journal.entries.drain(1..).collect() };
+        roll_back(&transaction.output_dir, &transaction.state_dir, &undone).unwrap();
+        assert_eq!(read(&dir, "first.txt").as_deref(), Some("new"));
+        assert_eq!(read(&dir, "second.txt").as_deref(), Some("old"));
+
+        assert!(recover(dir.path().to_str().unwrap()).unwrap());
+        assert!(!recover(dir.path().to_str().unwrap()).unwrap());
+        assert_eq!(read(&dir, "first.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "second.txt").as_deref(), Some("old"));
+        assert_eq!(read(&dir, "third.txt"), None);
+        assert_cleaned_up(&dir);
+    }
+
+    #[test]
+    fn replaces_file_by_directory_and_back() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "path", "file");
+
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        transaction.remove("path");
+        fs::write(transaction.stage("path/nested.txt"), "nested").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(read(&dir, "path/nested.txt").as_deref(), Some("nested"));
+
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        transaction.remove("path/nested.txt");
+        fs::write(transaction.stage("path"), "file").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(read(&dir, "path").as_deref(), Some("file"));
+        assert_cleaned_up(&dir);
+    }
+
+    #[test]
+    fn does_not_replace_directory_with_unknown_files() {
+        let dir = TempDir::new().unwrap();
+        write(&dir, "path/nested.txt", "nested");
+        write(&dir, "path/unknown.txt", "unknown");
+
+        let (mut transaction, _) = Transaction::begin(dir.path().to_str().unwrap()).unwrap();
+        transaction.remove("path/nested.txt");
+        fs::write(transaction.stage("path"), "file").unwrap();
+        assert!(transaction.commit().is_err());
+
+        assert_eq!(read(&dir, "path/nested.txt").as_deref(), Some("nested"));
+        assert_eq!(read(&dir, "path/unknown.txt").as_deref(), Some("unknown"));
+        assert_cleaned_up(&dir);
+    }
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashSet, fs, io::ErrorKind, path::Path};
+
+use console::style;
+use indicatif::ProgressBar;
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use walkdir::WalkDir;
+
+use crate::{cli, commands::{channel::resolve_version_name, switch::{get_version, metadata_matches}}, file_storage::FileStore, models::local_state::STATE_DIR_NAME};
+
+pub async fn run_verify(name: String, output_dir: String, storage_base_path: String, storage_client: impl FileStore) -> Result<(), Whatever> {
+    println!("{} {}Getting file list...", style("[1/3]").bold().dim(), cli::LOOKING_GLASS);
+
+    let version_name = resolve_version_name(&storage_client, &storage_base_path, &name, None).await.with_whatever_context(|_| format!("Could not resolve {name}"))?;
+    if version_name != name {
+        println!("      Channel {name} points to version {version_name}");
+    }
+
+    let version_def = get_version(&storage_client, &storage_base_path, &version_name).await.with_whatever_context(|_| format!("Could not get info of version {version_name}"))?;
+
+    let pb = ProgressBar::new(version_def.files.len() as u64);
+    pb.set_style(cli::PROGRESS_STYLE.clone());
+
+    println!("{} {}Verifying {} files...", style("[2/3]").bold().dim(), cli::HOURGLASS, version_def.files.len());
+    let mut missing = Vec::new(); let mut modified = Vec::new(); let mut metadata_changed = Vec::new(); let mut n_unchanged = 0;
+    for file in &version_def.files {
+        pb.set_message(format!("Verifying {}", file.r_path));
+
+        let full_path = Path::new(&output_dir).join(&file.r_path);
+        match fs::metadata(&full_path) {
+            Ok(existing_file) if !existing_file.is_file() => modified.push(file.r_path.clone()),
+            Ok(existing_file) if existing_file.len() != file.u_len => modified.push(file.r_path.clone()),
+            Ok(existing_file) => {
+                let sha256 = sha256::try_digest(&full_path).with_whatever_context(|_| format!("Could not get SHA256 hash for file {:#?}", full_path))?;
+                if sha256 != file.u_sha256 {
+                    modified.push(file.r_path.clone());
+                } else if !metadata_matches(file, &existing_file) {
+                    metadata_changed.push(file.r_path.clone());
+                } else {
+                    n_unchanged += 1;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => missing.push(file.r_path.clone()),
+            Err(e) => whatever!("Could not get metadata of file {:#?}: {e}", full_path),
+        }
+
+        pb.inc(1);
+    }
+
+    pb.finish_and_clear();
+
+    // Empty directories of the version are reported as missing too.
+    missing.extend(version_def.directories.iter().filter(|r_path| !Path::new(&output_dir).join(r_path).is_dir()).cloned());
+
+    println!("{} {}Looking for extra files...", style("[3/3]").bold().dim(), cli::CHECKLIST);
+    let known_files: HashSet<&str> = version_def.files.iter().map(|f| f.r_path.as_str()).collect();
+    let mut extra = Vec::new();
+    for entry in WalkDir::new(&output_dir).into_iter().filter_entry(|e| e.depth() != 1 || e.file_name() != STATE_DIR_NAME) {
+        let entry = entry.with_whatever_context(|_| format!("Failed to walk directory {}", output_dir))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let rel_file_path = entry
+            .path()
+            .strip_prefix(&output_dir)
+            .with_whatever_context(|_| format!("Could not strip path prefix {:#?} of {:#?}", output_dir, entry.path()))?
+            .to_str()
+            .with_whatever_context(|| format!("Could not convert path {:#?} stripped of {:#?} to string", output_dir, entry.path()))?
+            .to_string();
+        if !known_files.contains(rel_file_path.as_str()) {
+            extra.push(rel_file_path);
+        }
+    }
+    extra.sort();
+
+    print_file_list("Missing", &missing);
+    print_file_list("Modified", &modified);
+    print_file_list("Permissions or modification time changed", &metadata_changed);
+    print_file_list("Extra", &extra);
+
+    let n_drifted = missing.len() + modified.len() + metadata_changed.len() + extra.len();
+    if n_drifted > 0 {
+        println!("\n{}Found {} missing, {} modified, {} with changed permissions or modification time and {} extra files ({} unchanged).",
+            cli::CROSSMARK, missing.len(), modified.len(), metadata_changed.len(), extra.len(), n_unchanged);
+        whatever!("Folder {output_dir} does not match version {version_name}");
+    }
+
+    println!("\n{}Folder matches version {} ({} unchanged files).", cli::CHECKMARK, version_name, n_unchanged);
+
+    Ok(())
+}
+
+fn print_file_list(title: &str, files: &[String]) {
+    if files.is_empty() {
+        return;
+    }
+
+    println!("\n{} ({}):", style(title).bold(), files.len());
+    for file in files {
+        println!("  {file}");
+    }
+}
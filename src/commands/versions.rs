@@ -0,0 +1,99 @@
+use std::{collections::HashSet, path::Path};
+
+use chrono::{DateTime, Local, Utc};
+use console::style;
+use futures::{stream, StreamExt};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use snafu::{ResultExt, Whatever};
+
+use crate::{cli::{self, ListFormat}, commands::switch::get_version, file_storage::FileStore, signing::SIGNATURE_SUFFIX};
+
+/// The summary of a version, as listed by the `versions` command.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionSummary {
+    name: String,
+    display_version: Option<String>,
+    file_count: usize,
+    u_len: u64,
+    c_len: u64,
+    uploaded_at: Option<DateTime<Utc>>,
+    signed: bool,
+}
+
+pub async fn run_versions(storage_base_path: String, format: ListFormat, jobs: usize, storage_client: impl FileStore) -> Result<(), Whatever> {
+    let versions_dir = Path::new(&storage_base_path).join("versions");
+    let entries = storage_client.list(&versions_dir).await.with_whatever_context(|_| format!("Could not list versions in {:#?}", versions_dir))?;
+
+    let names: HashSet<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    let mut versions: Vec<_> = entries.iter().filter(|e| !e.name.ends_with(SIGNATURE_SUFFIX)).collect();
+    versions.sort_by(|a, b| a.last_modified.cmp(&b.last_modified).then_with(|| a.name.cmp(&b.name)));
+
+    // Get the definitions of up to `jobs` versions at once. Versions that can't be read (e.g. because they use a newer
+    // definition schema) are reported, but don't prevent listing the others.
+    let summaries: Vec<VersionSummary> = stream::iter(versions)
+        .map(|entry| {
+            let storage_client = &storage_client;
+            let storage_base_path = &storage_base_path;
+            let names = &names;
+            async move {
+                match get_version(storage_client, storage_base_path, &entry.name).await {
+                    Ok(version_def) => Some(VersionSummary {
+                        name: entry.name.clone(),
+                        display_version: version_def.display_version,
+                        file_count: version_def.files.len(),
+                        u_len: version_def.files.iter().map(|f| f.u_len).sum(),
+                        c_len: version_def.files.iter().map(|f| f.c_len).sum(),
+                        uploaded_at: entry.last_modified.map(DateTime::from),
+                        signed: names.contains(format!("{}{SIGNATURE_SUFFIX}", entry.name).as_str()),
+                    }),
+                    Err(e) => {
+                        eprintln!("{}Skipping version {}: {e}", cli::WARNING, entry.name);
+                        None
+                    },
+                }
+            }
+        })
+        .buffered(jobs)
+        .filter_map(|summary| async { summary })
+        .collect()
+        .await;
+
+    match format {
+        ListFormat::Json => {
+            let json = serde_json::to_string_pretty(&summaries).with_whatever_context(|_| "Could not convert versions to JSON")?;
+            println!("{json}");
+        },
+        ListFormat::Table if summaries.is_empty() => println!("No versions found in {:#?}.", versions_dir),
+        ListFormat::Table => print_table(&summaries),
+    }
+
+    Ok(())
+}
+
+fn print_table(summaries: &[VersionSummary]) {
+    let header = ["NAME", "DISPLAY VERSION", "FILES", "SIZE", "COMPRESSED", "UPLOADED", "SIGNED"];
+    let rows: Vec<[String; 7]> = summaries.iter().map(|s| [
+        s.name.clone(),
+        s.display_version.clone().unwrap_or_else(|| "-".to_owned()),
+        s.file_count.to_string(),
+        HumanBytes(s.u_len).to_string(),
+        HumanBytes(s.c_len).to_string(),
+        s.uploaded_at.map_or_else(|| "-".to_owned(), |t| DateTime::<Local>::from(t).format("%Y-%m-%d %H:%M").to_string()),
+        if s.signed { "yes" } else { "no" }.to_owned(),
+    ]).collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: &[String]| cells.iter().zip(widths).map(|(cell, width)| format!("{cell:<width$}")).collect::<Vec<_>>().join("  ").trim_end().to_owned();
+    println!("{}", style(format_row(&header.map(str::to_owned))).bold());
+    for row in &rows {
+        println!("{}", format_row(row));
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use brotli::enc::BrotliEncoderParams;
+
+/// Brotli quality used for compressing.
+const BROTLI_QUALITY: i32 = 8;
+
+/// Zstandard compression level used for compressing.
+const ZSTD_LEVEL: i32 = 12;
+
+/// Size of the buffer used for decompressing Brotli data.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The compression algorithms files can be stored with. The name of the algorithm is stored as `c_algo` in the version
+/// definition and the object metadata.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Codec {
+    Brotli,
+    Zstd,
+    /// Stores files as is, e.g. for media that is already compressed.
+    None,
+}
+
+impl Codec {
+    /// All supported algorithms.
+    pub const ALL: [Codec; 3] = [Codec::Brotli, Codec::Zstd, Codec::None];
+
+    /// Looks up an algorithm by its name, returns `None` if it isn't supported.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|codec| codec.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Brotli => "brotli",
+            Codec::Zstd => "zstd",
+            Codec::None => "none",
+        }
+    }
+
+    /// Compresses all data of `reader` to `writer`.
+    pub fn compress(&self, reader: &mut impl Read, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Codec::Brotli => {
+                let params = BrotliEncoderParams { quality: BROTLI_QUALITY, ..Default::default() };
+                brotli::BrotliCompress(reader, writer, &params)?;
+            },
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, ZSTD_LEVEL)?;
+                encoder.include_checksum(true)?;
+                io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            },
+            Codec::None => {
+                io::copy(reader, writer)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Returns a writer that decompresses the data written to it to `writer`.
+    pub fn decompressor<W: Write>(&self, writer: W) -> io::Result<Decompressor<W>> {
+        Ok(match self {
+            Codec::Brotli => Decompressor::Brotli(Box::new(brotli::DecompressorWriter::new(writer, BROTLI_BUFFER_SIZE))),
+            Codec::Zstd => Decompressor::Zstd(zstd::stream::zio::Writer::new(writer, zstd::stream::raw::Decoder::new()?)),
+            Codec::None => Decompressor::None(writer),
+        })
+    }
+}
+
+/// A writer that decompresses the data written to it, see [`Codec::decompressor`].
+pub enum Decompressor<W: Write> {
+    Brotli(Box<brotli::DecompressorWriter<W>>),
+    Zstd(zstd::stream::zio::Writer<W, zstd::stream::raw::Decoder<'static>>),
+    None(W),
+}
+
+impl<W: Write> Decompressor<W> {
+    /// Finishes decompressing and returns the inner writer. Fails if the data turned out to be incomplete, as far as the
+    /// algorithm can tell.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decompressor::Brotli(writer) => writer.into_inner().map_err(|_| io::Error::new(ErrorKind::InvalidData, "Incomplete or invalid Brotli data")),
+            Decompressor::Zstd(mut writer) => {
+                // Unlike flushing, finishing fails if the last frame is incomplete.
+                writer.finish()?;
+                let (mut writer, _) = writer.into_inner();
+                writer.flush()?;
+                Ok(writer)
+            },
+            Decompressor::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Decompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decompressor::Brotli(writer) => writer.write(buf),
+            Decompressor::Zstd(writer) => writer.write(buf),
+            Decompressor::None(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decompressor::Brotli(writer) => writer.flush(),
+            Decompressor::Zstd(writer) => writer.flush(),
+            Decompressor::None(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(codec: Codec, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        codec.compress(&mut &data[..], &mut compressed).unwrap();
+        compressed
+    }
+
+    fn decompress(codec: Codec, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressor = codec.decompressor(Vec::new())?;
+        decompressor.write_all(compressed)?;
+        decompressor.finish()
+    }
+
+    #[test]
+    fn round_trips_with_every_codec() {
+        let data = b"Some text that compresses well. ".repeat(1000);
+        for codec in Codec::ALL {
+            let compressed = compress(codec, &data);
+            if codec != Codec::None {
+                assert!(compressed.len() < data.len() / 10, "{codec:?}");
+            }
+            assert_eq!(decompress(codec, &compressed).unwrap(), data, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_data() {
+        let data = b"Some text that compresses well. ".repeat(1000);
+        for codec in [Codec::Brotli, Codec::Zstd] {
+            let compressed = compress(codec, &data);
+            assert!(decompress(codec, &compressed[..compressed.len() / 2]).is_err(), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn looks_up_codecs_by_name() {
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Codec::from_name("br"), None);
+    }
+}
@@ -0,0 +1,32 @@
+use object_store::azure::{MicrosoftAzure, MicrosoftAzureBuilder};
+use snafu::{ResultExt, Whatever};
+
+use crate::file_storage::object_store_client::ObjectStoreClient;
+
+pub type AzureClient = ObjectStoreClient<MicrosoftAzure>;
+
+impl AzureClient {
+    /// Creates a client for anonymous reads from a (public) container.
+    ///
+    /// Supports URLs like `https://my-account.blob.core.windows.net/my-container`, or `azure://my-container` in which case the
+    /// account is taken from the `AZURE_STORAGE_ACCOUNT_NAME` env var (or Azurite is used if `AZURE_STORAGE_USE_EMULATOR` is `true`).
+    pub fn new_from_url(url: &str) -> Result<AzureClient, Whatever> {
+        let store: MicrosoftAzure = MicrosoftAzureBuilder::from_env()
+            .with_url(url)
+            .with_skip_signature(true)
+            .build()
+            .with_whatever_context(|_| "Could not build Azure Blob Storage client")?;
+
+        Ok(AzureClient::new(store))
+    }
+
+    /// Creates a client for the container from the URL, using the credentials from the `AZURE_*` env vars.
+    pub fn new_from_env_and_url(url: &str) -> Result<AzureClient, Whatever> {
+        let store: MicrosoftAzure = MicrosoftAzureBuilder::from_env()
+            .with_url(url)
+            .build()
+            .with_whatever_context(|_| "Could not build Azure Blob Storage client")?;
+
+        Ok(AzureClient::new(store))
+    }
+}
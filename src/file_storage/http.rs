@@ -0,0 +1,218 @@
+use std::{collections::HashMap, io, path::{Component, Path}};
+
+use reqwest::{Client, StatusCode, header::{CONTENT_LENGTH, RANGE}};
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use tokio::io::{AsyncRead, AsyncSeek};
+use url::Url;
+
+use crate::file_storage::{self, FileStore};
+
+/// Scheme prefix that marks an URL as plain HTTP(S) storage instead of S3 (compatible) storage.
+pub const SCHEME_PREFIX: &str = "static+";
+
+/// A read-only file store that fetches files from any static web server or CDN using plain GET and HEAD requests.
+///
+/// Web servers don't provide the object metadata that is set on upload, so the returned metadata is always empty. This is
+/// fine for switching, as all required info (like `c_sha256`) is taken from the version definition instead.
+pub struct HttpClient {
+    client: Client,
+    base_url: Url,
+}
+
+impl HttpClient {
+    /// Creates a client for URLs like `static+https://my-example-cdn.com/updates`.
+    pub fn new_from_url(url: &str) -> Result<HttpClient, Whatever> {
+        let stripped_url = url.strip_prefix(SCHEME_PREFIX).with_whatever_context(|| format!("URL {url} does not start with {SCHEME_PREFIX}"))?;
+        let base_url = Url::parse(stripped_url).with_whatever_context(|_| format!("Could parse URL {url}"))?;
+        if base_url.cannot_be_a_base() {
+            whatever!("URL {url} cannot be used as base URL");
+        }
+
+        let client = Client::builder().build().with_whatever_context(|_| "Could not build HTTP client")?;
+
+        Ok(HttpClient { client, base_url })
+    }
+
+    fn file_url(&self, relative_path: &Path) -> Result<Url, Whatever> {
+        let mut url = self.base_url.clone();
+        {
+            let mut segments = url.path_segments_mut().ok().with_whatever_context(|| format!("URL {} cannot be used as base URL", self.base_url))?;
+            segments.pop_if_empty();
+            for component in relative_path.components() {
+                match component {
+                    Component::CurDir => {},
+                    Component::Normal(segment) => {
+                        segments.push(segment.to_str().with_whatever_context(|| format!("Could not convert path {:#?} to string", relative_path))?);
+                    },
+                    _ => whatever!("Path {:#?} is not a plain relative path", relative_path),
+                }
+            }
+        }
+
+        Ok(url)
+    }
+}
+
+impl FileStore for HttpClient {
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, _data_stream: T, _metadata: HashMap<&str, &str>) -> Result<(), Whatever> {
+        whatever!("Could not upload {:#?}, HTTP storage is read-only", relative_path)
+    }
+
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<file_storage::RemoteListEntry>, Whatever> {
+        whatever!("Could not list {:#?}, HTTP storage can't be listed", relative_dir)
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever> {
+        whatever!("Could not delete {:#?}, HTTP storage is read-only", relative_path)
+    }
+
+    async fn get_file_info(&self, relative_path: &Path) -> Result<Option<file_storage::RemoteFileInfo>, Whatever> {
+        let url = self.file_url(relative_path)?;
+        let response = self.client.head(url.clone()).send().await.with_whatever_context(|_| format!("Could not get file info for {url}"))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(file_storage::RemoteFileInfo {
+                c_len: content_length(&response)?,
+                metadata: HashMap::new(),
+            })),
+            // Keep client and server errors as `reqwest` errors, so they can be told apart (e.g. to retry server errors).
+            status => match response.error_for_status() {
+                Err(error) => Err(error).with_whatever_context(|_| format!("Could not get file info for {url}")),
+                Ok(_) => whatever!("Could not get file info for {url}: HTTP status {status}"),
+            },
+        }
+    }
+
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<file_storage::RemoteFile>, Whatever> {
+        let url = self.file_url(relative_path)?;
+        let mut request = self.client.get(url.clone());
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        let response = request.send().await.with_whatever_context(|_| format!("Could not get file {url}"))?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::PARTIAL_CONTENT => Ok(Some(file_storage::RemoteFile {
+                stream: Box::pin(futures::TryStreamExt::map_err(response.bytes_stream(), io::Error::other)),
+            })),
+            status if status.is_success() => {
+                // The server ignored the range (which it is allowed to), so skip the part before the offset ourselves.
+                let mut to_skip = offset;
+                let stream = futures::StreamExt::filter_map(response.bytes_stream(), move |res| {
+                    let chunk = match res {
+                        Ok(chunk) => {
+                            let skipped = to_skip.min(chunk.len() as u64);
+                            to_skip -= skipped;
+                            (skipped < chunk.len() as u64).then(|| Ok(chunk.slice(skipped as usize..)))
+                        },
+                        Err(error) => Some(Err(io::Error::other(error))),
+                    };
+                    async move { chunk }
+                });
+
+                Ok(Some(file_storage::RemoteFile { stream: Box::pin(stream) }))
+            },
+            status => match response.error_for_status() {
+                Err(error) => Err(error).with_whatever_context(|_| format!("Could not get file {url}")),
+                Ok(_) => whatever!("Could not get file {url}: HTTP status {status}"),
+            },
+        }
+    }
+}
+
+/// Gets the length from the `Content-Length` header of a HEAD response, as `reqwest` reports `0` for these. Responses to
+/// GET requests don't need the header (servers may send the body chunked), as their length is known from the version
+/// definition.
+fn content_length(response: &reqwest::Response) -> Result<u64, Whatever> {
+    let header = response.headers().get(CONTENT_LENGTH).with_whatever_context(|| format!("Response for {} has no Content-Length", response.url()))?;
+    let header = header.to_str().with_whatever_context(|_| format!("Response for {} has an invalid Content-Length", response.url()))?;
+    header.parse().with_whatever_context(|_| format!("Response for {} has an invalid Content-Length", response.url()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    const CONTENTS: &[u8] = b"0123456789abcdef";
+
+    /// Starts a web server that serves [`CONTENTS`] at `/updates/files/abc` like many static hosts do: GET responses are
+    /// sent chunked without a `Content-Length`, and ranges are only supported below `/ranged`.
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                let offset: usize = match request.split_once("range: bytes=") {
+                    Some((_, range)) if path.starts_with("/ranged/") => range.split('-').next().unwrap().parse().unwrap(),
+                    _ => 0,
+                };
+
+                let response = match (method, path.trim_start_matches("/ranged")) {
+                    ("head", "/updates/files/abc") => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", CONTENTS.len()).into_bytes(),
+                    ("get", "/updates/files/abc") => {
+                        let status = if offset > 0 { "206 Partial Content" } else { "200 OK" };
+                        let body = &CONTENTS[offset..];
+                        let mut response = format!("HTTP/1.1 {status}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n", body.len()).into_bytes();
+                        response.extend_from_slice(body);
+                        response.extend_from_slice(b"\r\n0\r\n\r\n");
+                        response
+                    },
+                    _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                socket.write_all(&response).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        format!("{SCHEME_PREFIX}http://{address}")
+    }
+
+    async fn read_from(client: &HttpClient, relative_path: &str, offset: u64) -> Vec<u8> {
+        let file = client.get_file_from(Path::new(relative_path), offset).await.unwrap().unwrap();
+        file.stream.map_ok(|bytes| bytes.to_vec()).try_concat().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn gets_files_without_content_length() {
+        let base_url = serve().await;
+        let client = HttpClient::new_from_url(&format!("{base_url}/updates")).unwrap();
+
+        assert_eq!(read_from(&client, "./files/abc", 0).await, CONTENTS);
+        // The server ignores the range, so the client skips the start itself.
+        assert_eq!(read_from(&client, "./files/abc", 10).await, &CONTENTS[10..]);
+        assert_eq!(client.get_file_info(Path::new("./files/abc")).await.unwrap().unwrap().c_len, CONTENTS.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn gets_ranges_of_files() {
+        let base_url = serve().await;
+        let client = HttpClient::new_from_url(&format!("{base_url}/ranged/updates/")).unwrap();
+
+        assert_eq!(read_from(&client, "./files/abc", 10).await, &CONTENTS[10..]);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_files() {
+        let base_url = serve().await;
+        let client = HttpClient::new_from_url(&format!("{base_url}/updates")).unwrap();
+
+        assert!(client.get_file(Path::new("./files/missing")).await.unwrap().is_none());
+        assert!(client.get_file_info(Path::new("./files/missing")).await.unwrap().is_none());
+        assert!(client.file_url(Path::new("../files/abc")).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, ErrorKind, SeekFrom}, path::{Path, PathBuf}};
+
+use bytes::Bytes;
+use snafu::{whatever, OptionExt, ResultExt, Whatever};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use url::Url;
+
+use crate::file_storage::{self, FileStore};
+
+/// Suffix of the sidecar file that holds the metadata of a stored file.
+const METADATA_SUFFIX: &str = ".meta.yaml";
+
+/// Suffix of the temporary file a file is written to while uploading it.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Size of the chunks in which files are streamed.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file store backed by a plain directory, e.g. on a local disk or network share.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new_from_url(url: &str) -> Result<LocalStore, Whatever> {
+        let parsed_url = Url::parse(url).with_whatever_context(|_| format!("Could parse URL {url}"))?;
+        let root = match parsed_url.to_file_path() {
+            Ok(root) => root,
+            Err(_) => whatever!("Could not get directory path from URL {url}"),
+        };
+
+        Ok(LocalStore { root })
+    }
+
+    fn full_path(&self, relative_path: &Path) -> PathBuf {
+        self.root.join(relative_path)
+    }
+
+    fn read_metadata(&self, full_path: &Path) -> Result<HashMap<String, String>, Whatever> {
+        let metadata_path = metadata_path(full_path);
+        match File::open(&metadata_path) {
+            Ok(file) => serde_yml::from_reader(file).with_whatever_context(|_| format!("Could not read metadata {:#?}", metadata_path)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => whatever!("Could not open metadata {:#?}: {e}", metadata_path),
+        }
+    }
+}
+
+impl FileStore for LocalStore {
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, mut data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever> {
+        let full_path = self.full_path(relative_path);
+        let parent = full_path.parent().with_whatever_context(|| format!("Could not get parent directory of {:#?}", full_path))?;
+        fs::create_dir_all(parent).with_whatever_context(|_| format!("Could not create directory {:#?}", parent))?;
+
+        // Write the metadata first and the data via a temporary file, so a file is never visible without its metadata or only partially.
+        let metadata_path = metadata_path(&full_path);
+        let metadata_file = File::create(&metadata_path).with_whatever_context(|_| format!("Could not create file {:#?}", metadata_path))?;
+        serde_yml::to_writer(metadata_file, &metadata).with_whatever_context(|_| format!("Could not write metadata to {:#?}", metadata_path))?;
+
+        let temp_path = with_suffix(&full_path, TEMP_SUFFIX);
+        let mut temp_file = tokio::fs::File::create(&temp_path).await.with_whatever_context(|_| format!("Could not create file {:#?}", temp_path))?;
+        tokio::io::copy(&mut data_stream, &mut temp_file).await.with_whatever_context(|_| format!("Could not write data stream of {:#?}", relative_path))?;
+        temp_file.sync_all().await.with_whatever_context(|_| format!("Could not flush {:#?}", temp_path))?;
+        fs::rename(&temp_path, &full_path).with_whatever_context(|_| format!("Could not move {:#?} to {:#?}", temp_path, full_path))?;
+
+        Ok(())
+    }
+
+    async fn get_file_info(&self, relative_path: &Path) -> Result<Option<file_storage::RemoteFileInfo>, Whatever> {
+        let full_path = self.full_path(relative_path);
+
+        match fs::metadata(&full_path) {
+            Ok(info) => Ok(Some(file_storage::RemoteFileInfo {
+                c_len: info.len(),
+                metadata: self.read_metadata(&full_path)?,
+            })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => whatever!("Could not get metadata of {:#?}: {e}", full_path),
+        }
+    }
+
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<file_storage::RemoteListEntry>, Whatever> {
+        let full_path = self.full_path(relative_dir);
+        list_dir(&full_path).with_whatever_context(|_| format!("Could not list {:#?}", full_path))
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever> {
+        let full_path = self.full_path(relative_path);
+
+        // Remove the data first, so a file is never visible without its metadata.
+        for path in [full_path.clone(), metadata_path(&full_path)] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => whatever!("Could not remove {:#?}: {e}", path),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<file_storage::RemoteFile>, Whatever> {
+        let full_path = self.full_path(relative_path);
+
+        let mut file = match tokio::fs::File::open(&full_path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => whatever!("Could not open {:#?}: {e}", full_path),
+        };
+        file.seek(SeekFrom::Start(offset)).await.with_whatever_context(|_| format!("Could not seek in {:#?}", full_path))?;
+
+        // The file is dropped after an error, which ends the stream.
+        let stream = futures::stream::unfold(Some(file), |file| async move {
+            let mut file = file?;
+            let mut buf = vec![0; CHUNK_SIZE];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), Some(file)))
+                },
+                Err(e) => Some((Err(e), None)),
+            }
+        });
+
+        Ok(Some(file_storage::RemoteFile { stream: Box::pin(stream) }))
+    }
+}
+
+/// Lists the stored files in a directory, leaving out metadata sidecar files and files that are still being uploaded.
+fn list_dir(full_path: &Path) -> io::Result<Vec<file_storage::RemoteListEntry>> {
+    let entries = match fs::read_dir(full_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        if !metadata.is_file() || name.ends_with(METADATA_SUFFIX) || name.ends_with(TEMP_SUFFIX) {
+            continue;
+        }
+
+        files.push(file_storage::RemoteListEntry { name, c_len: metadata.len(), last_modified: metadata.modified().ok() });
+    }
+
+    Ok(files)
+}
+
+fn metadata_path(full_path: &Path) -> PathBuf {
+    with_suffix(full_path, METADATA_SUFFIX)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::TryStreamExt;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn store(dir: &TempDir) -> LocalStore {
+        LocalStore::new_from_url(Url::from_directory_path(dir.path()).unwrap().as_str()).unwrap()
+    }
+
+    async fn read_from(store: &LocalStore, relative_path: &str, offset: u64) -> Vec<u8> {
+        let file = store.get_file_from(Path::new(relative_path), offset).await.unwrap().unwrap();
+        file.stream.map_ok(|bytes| bytes.to_vec()).try_concat().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_files_with_metadata() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+        store.upload_file(Path::new("files/abc"), Cursor::new(data.clone()), HashMap::from([("c_algo", "br")])).await.unwrap();
+
+        let info = store.get_file_info(Path::new("files/abc")).await.unwrap().unwrap();
+        assert_eq!(info.c_len, data.len() as u64);
+        assert_eq!(info.metadata, HashMap::from([("c_algo".to_owned(), "br".to_owned())]));
+        assert_eq!(read_from(&store, "files/abc", 0).await, data);
+        assert_eq!(read_from(&store, "files/abc", 100_000).await, data[100_000..]);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_files() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+
+        assert!(store.get_file_info(Path::new("files/missing")).await.unwrap().is_none());
+        assert!(store.get_file(Path::new("files/missing")).await.unwrap().is_none());
+        assert!(store.list(Path::new("missing")).await.unwrap().is_empty());
+        store.delete(Path::new("files/missing")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_and_deletes_files() {
+        let dir = TempDir::new().unwrap();
+        let store = store(&dir);
+        for name in ["a", "b"] {
+            store.upload_file(&Path::new("files").join(name), Cursor::new(name.as_bytes()), HashMap::new()).await.unwrap();
+        }
+        // Leftover of an interrupted upload.
+        fs::write(dir.path().join("files/c.tmp"), "partial").unwrap();
+
+        let mut names: Vec<String> = store.list(Path::new("files")).await.unwrap().into_iter().map(|f| f.name).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+
+        store.delete(Path::new("files/a")).await.unwrap();
+        assert!(store.get_file_info(Path::new("files/a")).await.unwrap().is_none());
+        assert!(!dir.path().join("files/a.meta.yaml").exists());
+        assert_eq!(store.list(Path::new("files")).await.unwrap().len(), 1);
+    }
+}
@@ -1,25 +1,182 @@
-use std::{collections::HashMap, io::Read, path::Path};
+use std::{collections::HashMap, error::Error, io, path::Path, time::SystemTime};
 
-use snafu::Whatever;
+use snafu::{whatever, ResultExt, Whatever};
 use futures::stream::BoxStream;
 use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncSeek};
+use url::Url;
 
+use crate::file_storage::{azure::AzureClient, http::HttpClient, local::LocalStore, s3::S3Client};
+
+pub mod azure;
+pub mod http;
+pub mod local;
+pub mod object_store_client;
+pub mod retry;
 pub mod s3;
 
 pub trait FileStore {
-    async fn upload_file<T: Read>(&self, relative_path: &Path, data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever>;
+    /// Uploads the data read from `data_stream` (starting at its current position), without reading it into memory as a
+    /// whole. The stream must be seekable, so a failed upload can be retried.
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever>;
     async fn get_file_info(&self, relative_path: &Path) -> Result<Option<RemoteFileInfo>, Whatever>;
-    async fn get_file(&self, relative_path: &Path) -> Result<Option<RemoteFile>, Whatever>;
+
+    /// Gets a file, streaming its contents.
+    async fn get_file(&self, relative_path: &Path) -> Result<Option<RemoteFile>, Whatever> {
+        self.get_file_from(relative_path, 0).await
+    }
+
+    /// Gets a file, streaming its contents starting at byte `offset` (e.g. to resume an interrupted download). Errors while
+    /// streaming are returned as the last item of the stream.
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<RemoteFile>, Whatever>;
+
+    /// Lists the files directly inside a directory. A directory that doesn't exist has no files.
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<RemoteListEntry>, Whatever>;
+
+    /// Deletes a file. Deleting a file that doesn't exist succeeds.
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever>;
 }
 
 #[derive(Debug)]
 pub struct RemoteFileInfo {
-    pub c_len: u32,
+    pub c_len: u64,
     pub metadata: HashMap<String, String>,
 }
 
+/// A file found by [`FileStore::list`].
+#[derive(Debug)]
+pub struct RemoteListEntry {
+    /// The name of the file, without the directory.
+    pub name: String,
+    pub c_len: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
 pub struct RemoteFile {
-    pub c_len: u32,
-    pub metadata: HashMap<String, String>,
-    pub stream: BoxStream<'static, Bytes>,
+    pub stream: BoxStream<'static, io::Result<Bytes>>,
+}
+
+/// A file store of any of the supported kinds, selected by URL scheme.
+pub enum StorageClient {
+    S3(S3Client),
+    Azure(AzureClient),
+    Http(HttpClient),
+    Local(LocalStore),
+}
+
+impl StorageClient {
+    /// Creates a client for reading from the given URL.
+    ///
+    /// `file://` URLs point to a local directory, `azure://` and `https://<account>.blob.core.windows.net` URLs to an Azure
+    /// Blob Storage container, `static+http(s)://` URLs to a plain web server, any other URL is used as endpoint and bucket
+    /// of S3 (compatible) storage.
+    pub fn new_from_url(url: &str) -> Result<StorageClient, Whatever> {
+        match StorageKind::from_url(url)? {
+            StorageKind::Local => Ok(StorageClient::Local(LocalStore::new_from_url(url)?)),
+            StorageKind::Azure => Ok(StorageClient::Azure(AzureClient::new_from_url(url)?)),
+            StorageKind::Http => Ok(StorageClient::Http(HttpClient::new_from_url(url)?)),
+            StorageKind::S3 => Ok(StorageClient::S3(S3Client::new_from_url(url)?)),
+        }
+    }
+
+    /// Creates a client for uploading to (or otherwise managing) the given URL, or the S3 storage configured using `AWS_*`
+    /// env vars if omitted.
+    pub fn new_for_upload(url: Option<&str>) -> Result<StorageClient, Whatever> {
+        match url {
+            None => Ok(StorageClient::S3(S3Client::new_from_env()?)),
+            Some(url) => match StorageKind::from_url(url)? {
+                StorageKind::Local => Ok(StorageClient::Local(LocalStore::new_from_url(url)?)),
+                StorageKind::Azure => Ok(StorageClient::Azure(AzureClient::new_from_env_and_url(url)?)),
+                StorageKind::Http => whatever!("Could not use {url}, HTTP storage can only be used for switching and verifying"),
+                StorageKind::S3 => Ok(StorageClient::S3(S3Client::new_from_env_and_url(url)?)),
+            },
+        }
+    }
+}
+
+impl FileStore for StorageClient {
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever> {
+        match self {
+            StorageClient::S3(client) => client.upload_file(relative_path, data_stream, metadata).await,
+            StorageClient::Azure(client) => client.upload_file(relative_path, data_stream, metadata).await,
+            StorageClient::Http(client) => client.upload_file(relative_path, data_stream, metadata).await,
+            StorageClient::Local(client) => client.upload_file(relative_path, data_stream, metadata).await,
+        }
+    }
+
+    async fn get_file_info(&self, relative_path: &Path) -> Result<Option<RemoteFileInfo>, Whatever> {
+        match self {
+            StorageClient::S3(client) => client.get_file_info(relative_path).await,
+            StorageClient::Azure(client) => client.get_file_info(relative_path).await,
+            StorageClient::Http(client) => client.get_file_info(relative_path).await,
+            StorageClient::Local(client) => client.get_file_info(relative_path).await,
+        }
+    }
+
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<RemoteFile>, Whatever> {
+        match self {
+            StorageClient::S3(client) => client.get_file_from(relative_path, offset).await,
+            StorageClient::Azure(client) => client.get_file_from(relative_path, offset).await,
+            StorageClient::Http(client) => client.get_file_from(relative_path, offset).await,
+            StorageClient::Local(client) => client.get_file_from(relative_path, offset).await,
+        }
+    }
+
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<RemoteListEntry>, Whatever> {
+        match self {
+            StorageClient::S3(client) => client.list(relative_dir).await,
+            StorageClient::Azure(client) => client.list(relative_dir).await,
+            StorageClient::Http(client) => client.list(relative_dir).await,
+            StorageClient::Local(client) => client.list(relative_dir).await,
+        }
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever> {
+        match self {
+            StorageClient::S3(client) => client.delete(relative_path).await,
+            StorageClient::Azure(client) => client.delete(relative_path).await,
+            StorageClient::Http(client) => client.delete(relative_path).await,
+            StorageClient::Local(client) => client.delete(relative_path).await,
+        }
+    }
+}
+
+/// Whether an error was caused by the storage denying access, judging by the errors it was caused by. Storage that can't be
+/// listed (like a public S3 bucket without `s3:ListBucket` access) reports missing files this way too.
+pub fn is_access_denied(error: &Whatever) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+            return error.status().is_some_and(|status| status.as_u16() == 403);
+        }
+        if let Some(error) = error.downcast_ref::<object_store::Error>() {
+            return matches!(error, object_store::Error::PermissionDenied { .. });
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+/// The kinds of storage, as derived from a storage URL.
+enum StorageKind {
+    S3,
+    Azure,
+    Http,
+    Local,
+}
+
+impl StorageKind {
+    fn from_url(url: &str) -> Result<StorageKind, Whatever> {
+        let parsed_url = Url::parse(url).with_whatever_context(|_| format!("Could parse URL {url}"))?;
+
+        match parsed_url.scheme() {
+            "file" => Ok(StorageKind::Local),
+            "azure" | "az" => Ok(StorageKind::Azure),
+            "static+http" | "static+https" => Ok(StorageKind::Http),
+            "https" if parsed_url.host_str().is_some_and(|host| host.ends_with(".blob.core.windows.net")) => Ok(StorageKind::Azure),
+            _ => Ok(StorageKind::S3),
+        }
+    }
 }
@@ -0,0 +1,172 @@
+use std::{borrow::Cow, collections::HashMap, io, path::{self, Path}};
+
+use object_store::{Attributes, GetOptions, GetRange, ObjectStore, PutMultipartOptions, PutOptions, PutPayload, WriteMultipart};
+use snafu::{OptionExt, ResultExt, Whatever};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek};
+
+use crate::file_storage::{self, FileStore};
+
+/// Size of the parts of multipart uploads. Data that fits in a single part is uploaded using a single request instead.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// Maximum number of parts of a multipart upload that are uploaded at once.
+const MAX_CONCURRENT_PARTS: usize = 4;
+
+/// A file store backed by any of the cloud storage services supported by `object_store`.
+pub struct ObjectStoreClient<S: ObjectStore> {
+    store: S,
+}
+
+impl<S: ObjectStore> ObjectStoreClient<S> {
+    pub fn new(store: S) -> ObjectStoreClient<S> {
+        ObjectStoreClient { store }
+    }
+}
+
+impl<S: ObjectStore> FileStore for ObjectStoreClient<S> {
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, mut data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever> {
+        let obj_stor_path = object_path(relative_path)?;
+
+        let mut attributes = Attributes::new();
+        for (key, value) in metadata.iter() {
+            attributes.insert(
+                object_store::Attribute::Metadata(Cow::Owned((*key).to_string())),
+                object_store::AttributeValue::from((*value).to_string())
+            );
+        }
+
+        // Small files are uploaded using a single request.
+        let mut part = read_part(&mut data_stream).await.with_whatever_context(|_| format!("Could not read data stream of {:#?}", relative_path))?;
+        if part.len() < PART_SIZE {
+            let options = PutOptions { attributes, ..Default::default() };
+            self.store.put_opts(&obj_stor_path, PutPayload::from(part), options).await.with_whatever_context(|_| format!("Could not upload {:#?} to storage", relative_path))?;
+            return Ok(());
+        }
+
+        // Larger files are uploaded in parts, so only a few parts are kept in memory at once.
+        let options = PutMultipartOptions { attributes, ..Default::default() };
+        let upload = self.store.put_multipart_opts(&obj_stor_path, options).await.with_whatever_context(|_| format!("Could not start upload of {:#?} to storage", relative_path))?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+        while !part.is_empty() {
+            if let Err(error) = writer.wait_for_capacity(MAX_CONCURRENT_PARTS).await {
+                let _ = writer.abort().await;
+                return Err(error).with_whatever_context(|_| format!("Could not upload {:#?} to storage", relative_path));
+            }
+            writer.write(&part);
+
+            part = match read_part(&mut data_stream).await {
+                Ok(part) => part,
+                Err(error) => {
+                    let _ = writer.abort().await;
+                    return Err(error).with_whatever_context(|_| format!("Could not read data stream of {:#?}", relative_path));
+                },
+            };
+        }
+        writer.finish().await.with_whatever_context(|_| format!("Could not upload {:#?} to storage", relative_path))?;
+
+        Ok(())
+    }
+
+    async fn get_file_info(&self, relative_path: &Path) -> Result<Option<file_storage::RemoteFileInfo>, Whatever> {
+        let obj_stor_path = object_path(relative_path)?;
+
+        let options = GetOptions { head: true, ..Default::default() };
+        let result = self.store.get_opts(&obj_stor_path, options).await;
+
+        match result {
+            Ok(info) => {
+                Ok(Some(file_storage::RemoteFileInfo {
+                    c_len: info.meta.size,
+                    metadata: info
+                        .attributes
+                        .iter()
+                        .filter_map(|(k, v)| {
+                            if let object_store::Attribute::Metadata(key) = k {
+                                Some((key.to_string(), v.to_string()))
+                            } else {
+                                None
+                            }
+                        })
+                        .collect::<HashMap<String, String>>(),
+                }))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(error) => Err(error).with_whatever_context(|_| format!("Could not get file info for {:#?} from storage", relative_path)),
+        }
+    }
+
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<file_storage::RemoteFile>, Whatever> {
+        let obj_stor_path = object_path(relative_path)?;
+
+        let options = GetOptions { range: (offset > 0).then_some(GetRange::Offset(offset)), ..Default::default() };
+        let result = self.store.get_opts(&obj_stor_path, options).await;
+
+        match result {
+            Ok(info) => {
+                Ok(Some(file_storage::RemoteFile {
+                    stream: Box::pin(futures::TryStreamExt::map_err(info.into_stream(), io::Error::other)),
+                }))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(error) => Err(error).with_whatever_context(|_| format!("Could not get file {:#?} from storage", relative_path)),
+        }
+    }
+
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<file_storage::RemoteListEntry>, Whatever> {
+        let obj_stor_path = object_path(relative_dir)?;
+
+        let result = self.store.list_with_delimiter(Some(&obj_stor_path)).await.with_whatever_context(|_| format!("Could not list {:#?} in storage", relative_dir))?;
+
+        Ok(result.objects.into_iter().filter_map(|meta| Some(file_storage::RemoteListEntry {
+            name: meta.location.filename()?.to_owned(),
+            c_len: meta.size,
+            last_modified: Some(meta.last_modified.into()),
+        })).collect())
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever> {
+        let obj_stor_path = object_path(relative_path)?;
+
+        match self.store.delete(&obj_stor_path).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(error) => Err(error).with_whatever_context(|_| format!("Could not delete {:#?} from storage", relative_path)),
+        }
+    }
+}
+
+/// Converts a relative path to the path of an object. A `.` component (like the default path prefix) is kept as an
+/// (encoded) part of the key, so objects stay where earlier versions of h3xup put them.
+fn object_path(relative_path: &Path) -> Result<object_store::path::Path, Whatever> {
+    let unix_path = relative_path.to_str().with_whatever_context(|| format!("Could not convert path {:#?} to string", relative_path))?.replace(path::MAIN_SEPARATOR_STR, "/");
+    Ok(object_store::path::Path::from(unix_path))
+}
+
+/// Reads up to [`PART_SIZE`] bytes. Only returns less if the end of the stream has been reached.
+async fn read_part<T: AsyncRead + Unpin>(data_stream: &mut T) -> std::io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    data_stream.take(PART_SIZE as u64).read_to_end(&mut part).await?;
+    Ok(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::TryStreamExt;
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_default_prefix_in_object_keys() {
+        let client = ObjectStoreClient::new(InMemory::new());
+
+        client.upload_file(Path::new("./versions/1.0.0"), Cursor::new(b"definition".to_vec()), HashMap::new()).await.unwrap();
+
+        let keys: Vec<String> = client.store.list(None).map_ok(|meta| meta.location.to_string()).try_collect().await.unwrap();
+        assert_eq!(keys, ["%2E/versions/1.0.0"]);
+        assert!(client.get_file_info(Path::new("./versions/1.0.0")).await.unwrap().is_some());
+        let names: Vec<String> = client.list(Path::new("./versions")).await.unwrap().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["1.0.0"]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashMap, error::Error, io::{self, ErrorKind, SeekFrom}, path::Path, time::Duration};
+
+use snafu::{ResultExt, Whatever};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+
+use crate::file_storage::{FileStore, RemoteFile, RemoteFileInfo, RemoteListEntry};
+
+/// Delay before the first retry, doubled for every following retry.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A file store that retries operations of the wrapped store that failed because of a transient error (like a dropped
+/// connection or a server error), waiting exponentially longer (with some jitter) between attempts.
+///
+/// Errors while streaming a downloaded file are not retried here, as the stream may already have been partly consumed.
+pub struct RetryingStore<S: FileStore> {
+    inner: S,
+    attempts: u32,
+}
+
+impl<S: FileStore> RetryingStore<S> {
+    /// Wraps a store, trying each operation at most `attempts` times.
+    pub fn new(inner: S, attempts: u32) -> RetryingStore<S> {
+        RetryingStore { inner, attempts: attempts.max(1) }
+    }
+
+    async fn retry<T>(&self, mut operation: impl AsyncFnMut() -> Result<T, Whatever>) -> Result<T, Whatever> {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.attempts && is_retryable(&e) => {
+                    tokio::time::sleep(backoff_delay(attempt)).await;
+                    attempt += 1;
+                },
+                Err(e) if attempt > 1 => return Err(e).with_whatever_context(|_| format!("Gave up after {attempt} attempts")),
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<S: FileStore> FileStore for RetryingStore<S> {
+    async fn upload_file<T: AsyncRead + AsyncSeek + Unpin>(&self, relative_path: &Path, mut data_stream: T, metadata: HashMap<&str, &str>) -> Result<(), Whatever> {
+        let start = data_stream.stream_position().await.with_whatever_context(|_| format!("Could not get position in data stream of {:#?}", relative_path))?;
+        self.retry(async || {
+            data_stream.seek(SeekFrom::Start(start)).await.with_whatever_context(|_| format!("Could not rewind data stream of {:#?}", relative_path))?;
+            self.inner.upload_file(relative_path, &mut data_stream, metadata.clone()).await
+        }).await
+    }
+
+    async fn get_file_info(&self, relative_path: &Path) -> Result<Option<RemoteFileInfo>, Whatever> {
+        self.retry(async || self.inner.get_file_info(relative_path).await).await
+    }
+
+    async fn get_file_from(&self, relative_path: &Path, offset: u64) -> Result<Option<RemoteFile>, Whatever> {
+        self.retry(async || self.inner.get_file_from(relative_path, offset).await).await
+    }
+
+    async fn list(&self, relative_dir: &Path) -> Result<Vec<RemoteListEntry>, Whatever> {
+        self.retry(async || self.inner.list(relative_dir).await).await
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<(), Whatever> {
+        self.retry(async || self.inner.delete(relative_path).await).await
+    }
+}
+
+/// Waits `BASE_DELAY * 2^(attempt - 1)` (at most [`MAX_DELAY`]), of which a random part of up to half, so clients that
+/// failed at the same time don't all retry at the same time.
+fn backoff_delay(attempt: u32) -> Duration {
+    let delay = BASE_DELAY.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_DELAY);
+    delay.mul_f64(1.0 - fastrand::f64() / 2.0)
+}
+
+/// Whether an error is (probably) transient, judging by the errors it was caused by. Errors of unknown origin (like
+/// invalid paths) are not retried.
+fn is_retryable(error: &Whatever) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return matches!(error.kind(),
+                ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe | ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::UnexpectedEof);
+        }
+        if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+            return match error.status() {
+                Some(status) => status.is_server_error() || status.as_u16() == 408 || status.as_u16() == 429,
+                None => error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
+            };
+        }
+        if let Some(error) = error.downcast_ref::<object_store::Error>() {
+            // Requests are already retried by `object_store` itself, but it gives up on longer outages. Transport and
+            // server errors are reported as generic errors, everything else (like missing permissions) is permanent.
+            return matches!(error, object_store::Error::Generic { .. });
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use snafu::whatever;
+
+    use super::*;
+
+    /// Wraps an error the way the file stores do.
+    fn wrap(source: impl Error + Send + Sync + 'static) -> Whatever {
+        Err::<(), _>(source).with_whatever_context(|_| "Could not get file").unwrap_err()
+    }
+
+    #[test]
+    fn retries_transient_io_errors() {
+        assert!(is_retryable(&wrap(io::Error::from(ErrorKind::ConnectionReset))));
+        assert!(is_retryable(&wrap(io::Error::from(ErrorKind::TimedOut))));
+    }
+
+    #[test]
+    fn does_not_retry_permanent_io_errors() {
+        assert!(!is_retryable(&wrap(io::Error::from(ErrorKind::NotFound))));
+        assert!(!is_retryable(&wrap(io::Error::from(ErrorKind::PermissionDenied))));
+    }
+
+    #[test]
+    fn retries_generic_object_store_errors_only() {
+        let generic = object_store::Error::Generic { store: "S3", source: "connection closed".into() };
+        let denied = object_store::Error::PermissionDenied { path: "versions/1".to_owned(), source: "access denied".into() };
+        assert!(is_retryable(&wrap(generic)));
+        assert!(!is_retryable(&wrap(denied)));
+    }
+
+    #[test]
+    fn looks_through_wrapping_errors() {
+        let error = Err::<(), _>(wrap(io::Error::from(ErrorKind::BrokenPipe))).with_whatever_context(|_| "Gave up").unwrap_err();
+        assert!(is_retryable(&error));
+    }
+
+    #[test]
+    fn does_not_retry_errors_of_unknown_origin() {
+        let error: Result<(), Whatever> = (|| whatever!("Path is not a plain relative path"))();
+        assert!(!is_retryable(&error.unwrap_err()));
+    }
+}
@@ -2,14 +2,17 @@ mod models;
 mod commands;
 mod file_storage;
 mod cli;
+mod compression;
+mod signing;
 
-use std::{collections::HashMap, env, fs::{self, File}, path::PathBuf};
+use std::{collections::HashMap, env, fs::{self, File}, future::Future, path::PathBuf, pin::Pin};
 
 use clap::Parser;
+use ed25519_dalek::SigningKey;
 use envie::Envie;
 use snafu::{whatever, OptionExt, ResultExt, Whatever};
 
-use crate::{cli::{Cli, Commands, CreateArgs, SwitchArgs, UpdateArgs}, file_storage::s3::S3Client, models::folder_config::*};
+use crate::{cli::{Cli, Commands, CreateArgs, DiffArgs, GcArgs, PromoteArgs, PruneCacheArgs, SwitchArgs, UpdateArgs, VerifyArgs, VersionsArgs}, commands::{create::CreateOptions, gc::GcOptions, switch::SwitchOptions}, compression::Codec, file_storage::{retry::RetryingStore, StorageClient}, models::{folder_config::*, version_definition::DefinitionVersion}};
 
 // ////////// //
 // Entrypoint //
@@ -26,6 +29,12 @@ async fn main() -> Result<(), Whatever> {
         Commands::Create(args) => try_run_create(args).await.with_whatever_context(|_| "Create command failed"),
         Commands::Switch(args) => try_run_switch(args).await.with_whatever_context(|_| "Switch command failed"),
         Commands::Update(args) => try_run_update(args).await.with_whatever_context(|_| "Update command failed"),
+        Commands::Verify(args) => try_run_verify(args).await.with_whatever_context(|_| "Verify command failed"),
+        Commands::PruneCache(args) => try_run_prune_cache(args).with_whatever_context(|_| "Prune cache command failed"),
+        Commands::Versions(args) => try_run_versions(args).await.with_whatever_context(|_| "Versions command failed"),
+        Commands::Gc(args) => try_run_gc(args).await.with_whatever_context(|_| "Gc command failed"),
+        Commands::Diff(args) => try_run_diff(args).await.with_whatever_context(|_| "Diff command failed"),
+        Commands::Promote(args) => try_run_promote(args).await.with_whatever_context(|_| "Promote command failed"),
     }?;
 
     Ok(())
@@ -39,105 +48,327 @@ async fn try_run_create(args: CreateArgs) -> Result<(), Whatever> {
     let input_dir = args.input_dir.or_else(|| env::var("UPDTR_INPUT_DIR").ok()).unwrap_or_else(|| ".".to_string());
     let path_prefix = args.filestore_path_prefix.clone().or_else(|| env::var("UPDTR_FILESTORE_PATH_PREFIX").ok()).unwrap_or_else(|| ".".to_string());
 
-    let file_storage = S3Client::new_from_env()?;
-    commands::create::run_create(args.display_version, &args.names, &input_dir, &path_prefix, file_storage).await?;
+    let s3_url = args.s3_url.or_else(|| env::var("UPDTR_S3_URL").ok());
+
+    let file_storage = RetryingStore::new(StorageClient::new_for_upload(s3_url.as_deref())?, get_attempts(args.retries)?);
+    // Without an explicit schema version, create picks the oldest one that can represent the files.
+    let definition_version = match args.definition_version.map(DefinitionVersion::try_from).transpose() {
+        Ok(definition_version) => definition_version,
+        Err(version) => whatever!("Definition schema version {version} is not supported"),
+    };
+    if args.chunked && let Some(definition_version) = definition_version && !definition_version.supports_chunks() {
+        whatever!("Chunked files are not supported by definition schema version {}", definition_version as u32);
+    }
+    let mut codecs = Vec::new();
+    for name in &args.compression {
+        match name.as_str() {
+            "smallest" => codecs.extend(Codec::ALL),
+            name => codecs.push(Codec::from_name(name).with_whatever_context(|| format!("Compression algorithm {name} is not supported"))?),
+        }
+    }
+    let signing_key = get_signing_key(args.signing_key)?;
+    let release_notes = match args.release_notes_file {
+        Some(notes_path) => Some(fs::read_to_string(&notes_path).with_whatever_context(|_| format!("Could not read release notes {notes_path}"))?),
+        None => args.release_notes,
+    };
+    if let Some(min_updater_version) = &args.min_updater_version {
+        semver::Version::parse(min_updater_version).with_whatever_context(|_| format!("Minimum updater version {min_updater_version} is not a valid version"))?;
+    }
+    let options = CreateOptions {
+        jobs: get_jobs(args.jobs)?,
+        definition_version,
+        delta_from: args.delta_from,
+        codecs,
+        chunked: args.chunked,
+        signing_key,
+        release_notes,
+        git_commit: args.git_commit,
+        build_host: args.build_host,
+        min_updater_version: args.min_updater_version,
+        preserve_mtime: args.preserve_mtime,
+    };
+
+    commands::create::run_create(args.display_version, &args.names, &input_dir, &path_prefix, options, file_storage).await?;
 
     Ok(())
 }
 
 async fn try_run_switch(args: SwitchArgs) -> Result<(), Whatever> {
+    let cache_max_size = get_cache_max_size(args.cache_max_size)?;
     run_switch_or_update(
         Some(args.name.clone()),
         args.s3_url.clone(),
         args.filestore_path_prefix.clone(),
         args.output_dir.clone(),
         "UPDTR_OUTPUT_DIR",
+        SwitchOptions {
+            jobs: get_jobs(args.jobs)?,
+            trusted_keys: args.trusted_key.clone(),
+            allow_unsigned: args.allow_unsigned,
+            cache_dir: (cache_max_size > 0).then(get_cache_dir).transpose()?,
+            cache_max_size,
+            dry_run: args.dry_run.then_some(args.format),
+        },
+        get_attempts(args.retries)?,
         get_config,
-        |name, dir, prefix, storage| Box::pin(async move {
-            let _ = commands::switch::run_switch(name, dir, prefix, storage).await;
-        }),
+        |name, dir, prefix, options, storage| Box::pin(commands::switch::run_switch(name, dir, prefix, options, storage)),
     ).await?;
 
     Ok(())
 }
 
 async fn try_run_update(args: UpdateArgs) -> Result<(), Whatever> {
+    let cache_max_size = get_cache_max_size(args.cache_max_size)?;
     run_switch_or_update(
         args.name.clone(),
         args.s3_url.clone(),
         args.filestore_path_prefix.clone(),
         args.output_dir.clone(),
         "UPDTR_OUTPUT_DIR",
+        SwitchOptions {
+            jobs: get_jobs(args.jobs)?,
+            trusted_keys: args.trusted_key.clone(),
+            allow_unsigned: args.allow_unsigned,
+            cache_dir: (cache_max_size > 0).then(get_cache_dir).transpose()?,
+            cache_max_size,
+            dry_run: args.dry_run.then_some(args.format),
+        },
+        get_attempts(args.retries)?,
         get_config,
-        |name, dir, prefix, storage| Box::pin(async move {
-            let _ = commands::switch::run_switch(name, dir, prefix, storage).await;
-        }),
+        |name, dir, prefix, options, storage| Box::pin(commands::switch::run_switch(name, dir, prefix, options, storage)),
     ).await?;
 
     Ok(())
 }
 
+async fn try_run_verify(args: VerifyArgs) -> Result<(), Whatever> {
+    let config = get_config()?;
+    let target = resolve_folder_target(&config, args.name, args.s3_url, args.filestore_path_prefix, args.output_dir, "UPDTR_OUTPUT_DIR")?;
+
+    let file_storage = RetryingStore::new(StorageClient::new_from_url(&target.s3_url)?, get_attempts(args.retries)?);
+    commands::verify::run_verify(target.version, target.output_dir, target.path_prefix, file_storage).await?;
+
+    Ok(())
+}
+
+async fn try_run_versions(args: VersionsArgs) -> Result<(), Whatever> {
+    let path_prefix = args.filestore_path_prefix.or_else(|| env::var("UPDTR_FILESTORE_PATH_PREFIX").ok()).unwrap_or_else(|| ".".to_string());
+    let s3_url = args.s3_url.or_else(|| env::var("UPDTR_S3_URL").ok());
+
+    let file_storage = RetryingStore::new(StorageClient::new_for_upload(s3_url.as_deref())?, get_attempts(args.retries)?);
+    commands::versions::run_versions(path_prefix, args.format, get_jobs(args.jobs)?, file_storage).await?;
+
+    Ok(())
+}
+
+async fn try_run_gc(args: GcArgs) -> Result<(), Whatever> {
+    let path_prefix = args.filestore_path_prefix.or_else(|| env::var("UPDTR_FILESTORE_PATH_PREFIX").ok()).unwrap_or_else(|| ".".to_string());
+    let s3_url = args.s3_url.or_else(|| env::var("UPDTR_S3_URL").ok());
+
+    let file_storage = RetryingStore::new(StorageClient::new_for_upload(s3_url.as_deref())?, get_attempts(args.retries)?);
+    let options = GcOptions {
+        dry_run: args.dry_run,
+        grace_period: args.grace_period,
+        jobs: get_jobs(args.jobs)?,
+    };
+    commands::gc::run_gc(path_prefix, options, file_storage).await?;
+
+    Ok(())
+}
+
+async fn try_run_diff(args: DiffArgs) -> Result<(), Whatever> {
+    let path_prefix = args.filestore_path_prefix.or_else(|| env::var("UPDTR_FILESTORE_PATH_PREFIX").ok()).unwrap_or_else(|| ".".to_string());
+    let s3_url = args.s3_url.or_else(|| env::var("UPDTR_S3_URL").ok());
+
+    let file_storage = RetryingStore::new(StorageClient::new_for_upload(s3_url.as_deref())?, get_attempts(args.retries)?);
+    commands::diff::run_diff(path_prefix, args.from, args.to, args.format, file_storage).await?;
+
+    Ok(())
+}
+
+async fn try_run_promote(args: PromoteArgs) -> Result<(), Whatever> {
+    let path_prefix = args.filestore_path_prefix.or_else(|| env::var("UPDTR_FILESTORE_PATH_PREFIX").ok()).unwrap_or_else(|| ".".to_string());
+    let s3_url = args.s3_url.or_else(|| env::var("UPDTR_S3_URL").ok());
+
+    let file_storage = RetryingStore::new(StorageClient::new_for_upload(s3_url.as_deref())?, get_attempts(args.retries)?);
+    commands::channel::run_promote(args.version, args.channel, path_prefix, get_signing_key(args.signing_key)?, file_storage).await?;
+
+    Ok(())
+}
+
+fn try_run_prune_cache(args: PruneCacheArgs) -> Result<(), Whatever> {
+    commands::cache::run_prune_cache(get_cache_dir()?, args.max_size)?;
+
+    Ok(())
+}
+
 // ///////////////// //
 // Operation helpers //
 // ///////////////// //
 
+const DEFAULT_JOBS: usize = 4;
+
+/// Default size the download cache is pruned to: 1 GiB.
+const DEFAULT_CACHE_MAX_SIZE: u64 = 1 << 30;
+
+/// Default number of times a failed storage operation is retried.
+const DEFAULT_RETRIES: u32 = 3;
+
+type SwitchRunner = fn(String, String, String, SwitchOptions, RetryingStore<StorageClient>) -> Pin<Box<dyn Future<Output = Result<(), Whatever>>>>;
+
+#[allow(clippy::too_many_arguments)]
 async fn run_switch_or_update(
     name: Option<String>,
     s3_url: Option<String>,
     filestore_path_prefix: Option<String>,
     output_dir: Option<String>,
     env_output_dir: &str,
+    mut options: SwitchOptions,
+    storage_attempts: u32,
     config_getter: fn() -> Result<Config, Whatever>,
-    run_switch: fn(String, String, String, S3Client) -> std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>,
+    run_switch: SwitchRunner,
 ) -> Result<(), Whatever> {
     let mut config = config_getter()?;
+    let target = resolve_folder_target(&config, name, s3_url, filestore_path_prefix, output_dir, env_output_dir)?;
+    let folder_config = config.folders.get(&target.output_dir);
+
+    // Keys passed on the CLI replace the keys that were trusted for the folder before.
+    if options.trusted_keys.is_empty() {
+        options.trusted_keys = folder_config.map(|f| f.trusted_keys.clone()).unwrap_or_default();
+    }
+    let trusted_keys = options.trusted_keys.clone();
+    let dry_run = options.dry_run.is_some();
+
+    let file_storage = RetryingStore::new(StorageClient::new_from_url(&target.s3_url)?, storage_attempts);
+    run_switch(target.version.clone(), target.output_dir.clone(), target.path_prefix.clone(), options, file_storage).await?;
+
+    if !dry_run && folder_config.is_none_or(|f| f.last_installed_version != target.version || f.s3_url != target.s3_url || f.trusted_keys != trusted_keys) {
+        config.folders.insert(
+            target.output_dir,
+            FolderConfig {
+                last_installed_version: target.version,
+                s3_url: target.s3_url,
+                storage_path_prefix: Some(target.path_prefix),
+                trusted_keys,
+            },
+        );
+        save_config(config).with_whatever_context(|_| "Switch or update succeeded, but could not update folder config")?;
+    }
+
+    Ok(())
+}
+
+/// Gets the number of files to process at once from the CLI args or `UPDTR_JOBS` env var.
+fn get_jobs(jobs: Option<usize>) -> Result<usize, Whatever> {
+    let jobs = match jobs {
+        Some(jobs) => jobs,
+        None => match env::var("UPDTR_JOBS") {
+            Ok(jobs) => jobs.parse().with_whatever_context(|_| format!("Could not parse UPDTR_JOBS value {jobs}"))?,
+            Err(_) => DEFAULT_JOBS,
+        },
+    };
+
+    if jobs == 0 {
+        whatever!("The number of jobs must be at least 1");
+    }
+
+    Ok(jobs)
+}
+
+/// Gets the size the download cache is pruned to from the CLI args or `UPDTR_CACHE_MAX_SIZE` env var.
+fn get_cache_max_size(cache_max_size: Option<u64>) -> Result<u64, Whatever> {
+    match cache_max_size {
+        Some(cache_max_size) => Ok(cache_max_size),
+        None => match env::var("UPDTR_CACHE_MAX_SIZE") {
+            Ok(size) => match cli::parse_size(&size) {
+                Ok(size) => Ok(size),
+                Err(e) => whatever!("Could not parse UPDTR_CACHE_MAX_SIZE value {size}: {e}"),
+            },
+            Err(_) => Ok(DEFAULT_CACHE_MAX_SIZE),
+        },
+    }
+}
+
+/// Gets the directory of the download cache from the `UPDTR_CACHE_DIR` env var, or the user's cache directory.
+fn get_cache_dir() -> Result<PathBuf, Whatever> {
+    if let Ok(cache_dir) = env::var("UPDTR_CACHE_DIR") {
+        return Ok(PathBuf::from(cache_dir));
+    }
+
+    let cache_dir = dirs::cache_dir().with_whatever_context(|| "Could not resolve cache directory")?;
+    Ok(cache_dir.join("h3xUpdtr").join("blobs"))
+}
+
+/// Gets the number of times a storage operation is tried from the CLI args or `UPDTR_RETRIES` env var.
+fn get_attempts(retries: Option<u32>) -> Result<u32, Whatever> {
+    let retries = match retries {
+        Some(retries) => retries,
+        None => match env::var("UPDTR_RETRIES") {
+            Ok(retries) => retries.parse().with_whatever_context(|_| format!("Could not parse UPDTR_RETRIES value {retries}"))?,
+            Err(_) => DEFAULT_RETRIES,
+        },
+    };
+
+    Ok(retries.saturating_add(1))
+}
+
+/// Reads the signing key from the file passed on the CLI or from the `UPDTR_SIGNING_KEY` env var, if any.
+fn get_signing_key(key_path: Option<String>) -> Result<Option<SigningKey>, Whatever> {
+    let signing_key = match key_path {
+        Some(key_path) => Some(fs::read_to_string(&key_path).with_whatever_context(|_| format!("Could not read signing key {key_path}"))?),
+        None => env::var("UPDTR_SIGNING_KEY").ok(),
+    };
+
+    signing_key.map(|key| signing::parse_signing_key(&key)).transpose()
+}
+
+/// A folder and the version and storage location it should be compared with, resolved from CLI args, env vars and config.
+struct FolderTarget {
+    output_dir: String,
+    version: String,
+    s3_url: String,
+    path_prefix: String,
+}
+
+fn resolve_folder_target(
+    config: &Config,
+    name: Option<String>,
+    s3_url: Option<String>,
+    filestore_path_prefix: Option<String>,
+    output_dir: Option<String>,
+    env_output_dir: &str,
+) -> Result<FolderTarget, Whatever> {
     let output_dir = output_dir.or_else(|| env::var(env_output_dir).ok()).unwrap_or_else(|| ".".to_string());
     let output_dir_for_path = output_dir.clone();
     let canonical_output_dir = fs::canonicalize(PathBuf::from(output_dir_for_path)).with_whatever_context(|_| format!("Could not canonicalize {output_dir}"))?;
     let canonical_output_dir_str = canonical_output_dir.to_str().with_whatever_context(|| format!("Could not convert path {:#?} to string", canonical_output_dir))?.to_owned();
     let folder_config = config.folders.get(&canonical_output_dir_str);
 
-    let version = match &name {
-        Some(name) => name.clone(),
+    let version = match name {
+        Some(name) => name,
         None => match folder_config {
             Some(folder_config) => folder_config.last_installed_version.clone(),
             None => whatever!("No version name provided and no name found in the configuration"),
         },
     };
 
-    let s3_url = match &s3_url {
-        Some(s3_url) => s3_url.clone(),
+    let s3_url = match s3_url {
+        Some(s3_url) => s3_url,
         None => match folder_config {
             Some(folder_config) => folder_config.s3_url.clone(),
             None => whatever!("No S3 URL provided and no S3 URL found in the configuration"),
         },
     };
 
-    let path_prefix_ref = match filestore_path_prefix.as_ref() {
-        Some(path_prefix) => path_prefix.clone(),
+    let path_prefix = match filestore_path_prefix {
+        Some(path_prefix) => path_prefix,
         None => match folder_config {
             Some(folder_config) => folder_config.storage_path_prefix.clone().unwrap_or_else(|| ".".to_string()),
             None => ".".to_string(),
         },
     };
 
-    let file_storage = S3Client::new_from_url(&s3_url)?;
-    let version_clone = version.clone();
-    run_switch(version_clone, canonical_output_dir_str.clone(), path_prefix_ref.clone(), file_storage).await;
-
-    if folder_config.is_none_or(|f| f.last_installed_version != version || f.s3_url != s3_url) {
-        config.folders.insert(
-            canonical_output_dir_str.clone(),
-            FolderConfig {
-                last_installed_version: version,
-                s3_url: s3_url,
-                storage_path_prefix: Some(path_prefix_ref),
-            },
-        );
-        save_config(config).with_whatever_context(|_| "Switch or update succeeded, but could not update folder config")?;
-    }
-
-    Ok(())
+    Ok(FolderTarget { output_dir: canonical_output_dir_str, version, s3_url, path_prefix })
 }
 
 // ////////////// //
@@ -145,7 +376,7 @@ async fn run_switch_or_update(
 // ////////////// //
 
 fn get_config_path() -> Result<PathBuf, Whatever> {
-    let config_dir = dirs::config_local_dir().with_whatever_context(|| format!("Could not resolve config local directory"))?;
+    let config_dir = dirs::config_local_dir().with_whatever_context(|| "Could not resolve config local directory")?;
     Ok(config_dir.join("h3xUpdtr").join("config.yaml"))
 }
 
@@ -0,0 +1,31 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A channel (like `stable`), pointing to the version that is currently released on it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Channel {
+    /// Incremented each time the channel is pointed to another version. As it is signed along with the rest of the
+    /// channel, clients can refuse a channel that is older than one they have seen before (`0` for channels that were
+    /// created by older versions of h3xup).
+    #[serde(default)]
+    pub sequence: u64,
+
+    /// The version the channel points to.
+    pub current: ChannelTarget,
+
+    /// The versions the channel pointed to before, most recent first.
+    #[serde(default)]
+    pub history: Vec<ChannelTarget>,
+}
+
+/// A version a channel points (or pointed) to.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelTarget {
+    /// The name of the version.
+    pub version: String,
+
+    /// When the channel was pointed to the version.
+    pub promoted_at: DateTime<Utc>,
+}
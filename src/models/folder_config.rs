@@ -12,4 +12,6 @@ pub struct FolderConfig {
     pub last_installed_version: String,
     pub s3_url: String,
     pub storage_path_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_keys: Vec<String>,
 }
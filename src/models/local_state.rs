@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the directory (relative to the output directory) in which the updater keeps its local state.
+pub const STATE_DIR_NAME: &str = ".h3xup";
+
+/// Name of the file (relative to the state directory) that stores the installed state.
+pub const STATE_FILE_NAME: &str = "state.yaml";
+
+/// The state of a folder as left behind by the last successful switch.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalState {
+    /// The name of the version that was installed.
+    pub version_name: String,
+
+    /// Relative paths of all files that were installed by the updater.
+    pub files: Vec<String>,
+
+    /// Relative paths of the empty directories that were created by the updater.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<String>,
+
+    /// The sequence number of each channel the folder was switched with, as last seen. Older channels are refused, so an
+    /// old channel (and its signature) that is put back can't roll the folder back to an old version.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channel_sequences: BTreeMap<String, u64>,
+}
+
+/// Name of the file (relative to the state directory) that stores the journal of a switch that is being committed.
+pub const JOURNAL_FILE_NAME: &str = "journal.yaml";
+
+/// Name of the directory (relative to the state directory) in which new files are prepared before being committed.
+pub const STAGING_DIR_NAME: &str = "staging";
+
+/// Name of the directory (relative to the state directory) in which replaced files are kept until a commit has finished.
+pub const BACKUP_DIR_NAME: &str = "backup";
+
+/// The journal of a switch that is being committed. As long as this exists, the commit has not finished and will be rolled back.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Journal {
+    /// The operations of the commit, in the order they are applied.
+    pub entries: Vec<JournalEntry>,
+}
+
+/// A single file operation of a commit.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JournalEntry {
+    /// Relative path of the file in the output directory.
+    pub r_path: String,
+
+    /// Name of the new file in the staging directory, or `null` if the file is removed.
+    pub staged: Option<String>,
+
+    /// Name the existing file gets in the backup directory.
+    pub backup: String,
+}
@@ -1,2 +1,4 @@
 pub mod version_definition;
 pub mod folder_config;
+pub mod local_state;
+pub mod channel;
@@ -1,11 +1,14 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::compression::Codec;
+
 /// The definition of a version.
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VersionDefinition {
-    /// The version of the definition schema. Always `1` for now.
+    /// The version of the definition schema.
     pub version: DefinitionVersion,
 
     /// The display version of the application. This is only used for cosmetic purpose and can be `null`.
@@ -13,16 +16,97 @@ pub struct VersionDefinition {
     /// e.g. "1.2.3".
     pub display_version: Option<String>,
 
+    /// When the version was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+
+    /// Release notes, shown when switching to the version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_notes: Option<String>,
+
+    /// The Git commit the version was built from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+
+    /// The host the version was built on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_host: Option<String>,
+
+    /// The oldest version of h3xup that can switch to this version, newer versions of h3xup refuse it if they are older.
+    ///
+    /// e.g. "0.3.0".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_updater_version: Option<String>,
+
     /// The files this version consists of.
     pub files: Vec<FileDefinition>,
+
+    /// Relative paths of the empty directories of this version, so they are created when switching.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<String>,
+}
+
+/// Only the schema version of a version definition, used to check whether it is supported before parsing the rest.
+#[derive(Deserialize)]
+pub struct DefinitionHeader {
+    /// The version of the definition schema.
+    pub version: u32,
 }
 
 /// The definition schema versions.
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug)]
 #[repr(u32)]
 pub enum DefinitionVersion {
     /// Version 1.
     Version1 = 1,
+
+    /// Version 2, supports files larger than 4 GiB, compression algorithms other than Brotli, patches, file permissions
+    /// and modification times, empty directories and a minimum updater version.
+    Version2 = 2,
+
+    /// Version 3, supports chunked files.
+    Version3 = 3,
+}
+
+impl DefinitionVersion {
+    /// The largest file size (both compressed and uncompressed) that can be represented in this schema version.
+    pub fn max_file_len(&self) -> u64 {
+        match self {
+            DefinitionVersion::Version1 => u32::MAX as u64,
+            DefinitionVersion::Version2 | DefinitionVersion::Version3 => u64::MAX,
+        }
+    }
+
+    /// The oldest schema version that can represent a version definition. Versions of h3xup that only know schema version
+    /// 1 decompress every file using Brotli and ignore fields they don't know, so anything they would misread or skip
+    /// requires version 2.
+    pub fn oldest_supporting(version: &VersionDefinition) -> DefinitionVersion {
+        if version.files.iter().any(|f| !f.chunks.is_empty()) {
+            DefinitionVersion::Version3
+        } else if version.files.iter().any(|f| !f.is_readable_by_version1()) || !version.directories.is_empty() || version.min_updater_version.is_some() {
+            DefinitionVersion::Version2
+        } else {
+            DefinitionVersion::Version1
+        }
+    }
+
+    /// Whether files can be stored as chunks in this schema version.
+    pub fn supports_chunks(&self) -> bool {
+        *self as u32 >= DefinitionVersion::Version3 as u32
+    }
+}
+
+impl TryFrom<u32> for DefinitionVersion {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DefinitionVersion::Version1),
+            2 => Ok(DefinitionVersion::Version2),
+            3 => Ok(DefinitionVersion::Version3),
+            _ => Err(value),
+        }
+    }
 }
 
 /// The definition of a version's file.
@@ -33,17 +117,157 @@ pub struct FileDefinition {
     pub r_path: String,
 
     /// File size of the uncompressed file.
-    pub u_len: u32,
+    pub u_len: u64,
 
     /// SHA256 hash of the uncompresed file.
     pub u_sha256: String,
 
-    /// Algorithm used for compressing the data.
+    /// Algorithm used for compressing the data, or [`CHUNKED_ALGO`] for chunked files.
     pub c_algo: String,
 
-    /// File size of the compressed file.
-    pub c_len: u32,
+    /// File size of the compressed file. For chunked files, this is the total size of the compressed chunks.
+    pub c_len: u64,
+
+    /// SHA256 hash of the compressed file, `null` for chunked files.
+    pub c_sha256: Option<String>,
 
-    /// SHA256 hash of the compressed file.
+    /// Unix permission bits of the file (e.g. 493 for `0o755`), `null` if unknown (e.g. when created on Windows).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+
+    /// Modification time of the file, only recorded if requested when creating the version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Utc>>,
+
+    /// Patches that turn files of previous versions into this file, can be empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<PatchDefinition>,
+
+    /// The chunks the file consists of, in order (schema version 3 and later). Empty if the file is stored as a whole.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkDefinition>,
+}
+
+impl FileDefinition {
+    /// Whether versions of h3xup that only know definition schema version 1 can switch to this file correctly.
+    fn is_readable_by_version1(&self) -> bool {
+        self.c_algo == Codec::Brotli.name()
+            && self.u_len.max(self.c_len) <= DefinitionVersion::Version1.max_file_len()
+            && self.patches.is_empty()
+            && self.mode.is_none()
+            && self.mtime.is_none()
+    }
+}
+
+/// The `c_algo` of files that are stored as chunks instead of as a whole.
+pub const CHUNKED_ALGO: &str = "chunked";
+
+/// The definition of a chunk of a file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkDefinition {
+    /// Size of the uncompressed chunk.
+    pub u_len: u64,
+
+    /// SHA256 hash of the uncompressed chunk.
+    pub u_sha256: String,
+
+    /// Algorithm used for compressing the chunk.
+    pub c_algo: String,
+
+    /// Size of the compressed chunk.
+    pub c_len: u64,
+
+    /// SHA256 hash of the compressed chunk.
     pub c_sha256: String,
 }
+
+/// The definition of a patch that turns a file of a previous version into a version's file.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchDefinition {
+    /// SHA256 hash of the uncompressed file the patch applies to.
+    pub from_u_sha256: String,
+
+    /// Algorithm used for creating the patch.
+    pub p_algo: String,
+
+    /// File size of the patch.
+    pub p_len: u64,
+
+    /// SHA256 hash of the patch.
+    pub p_sha256: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> FileDefinition {
+        FileDefinition {
+            r_path: "a.txt".to_owned(),
+            u_len: 100,
+            u_sha256: "u".to_owned(),
+            c_algo: Codec::Brotli.name().to_owned(),
+            c_len: 50,
+            c_sha256: Some("c".to_owned()),
+            mode: None,
+            mtime: None,
+            patches: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    fn version(files: Vec<FileDefinition>) -> VersionDefinition {
+        VersionDefinition {
+            version: DefinitionVersion::Version1,
+            display_version: None,
+            created_at: Some(Utc::now()),
+            release_notes: Some("Notes".to_owned()),
+            git_commit: None,
+            build_host: None,
+            min_updater_version: None,
+            files,
+            directories: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn uses_version1_for_plain_brotli_files() {
+        assert_eq!(DefinitionVersion::oldest_supporting(&version(vec![file()])), DefinitionVersion::Version1);
+        assert_eq!(DefinitionVersion::oldest_supporting(&version(Vec::new())), DefinitionVersion::Version1);
+    }
+
+    #[test]
+    fn uses_version2_for_what_version1_clients_misread() {
+        let large = FileDefinition { u_len: u32::MAX as u64 + 1, ..file() };
+        let zstd = FileDefinition { c_algo: Codec::Zstd.name().to_owned(), ..file() };
+        let uncompressed = FileDefinition { c_algo: Codec::None.name().to_owned(), ..file() };
+        let patched = FileDefinition {
+            patches: vec![PatchDefinition { from_u_sha256: "p".to_owned(), p_algo: "zstd".to_owned(), p_len: 5, p_sha256: "p".to_owned() }],
+            ..file()
+        };
+        let executable = FileDefinition { mode: Some(0o755), ..file() };
+        let with_mtime = FileDefinition { mtime: Some(Utc::now()), ..file() };
+        for special_file in [large, zstd, uncompressed, patched, executable, with_mtime] {
+            assert_eq!(DefinitionVersion::oldest_supporting(&version(vec![file(), special_file])), DefinitionVersion::Version2);
+        }
+
+        let with_directories = VersionDefinition { directories: vec!["empty".to_owned()], ..version(vec![file()]) };
+        assert_eq!(DefinitionVersion::oldest_supporting(&with_directories), DefinitionVersion::Version2);
+        let with_min_updater_version = VersionDefinition { min_updater_version: Some("0.3.0".to_owned()), ..version(vec![file()]) };
+        assert_eq!(DefinitionVersion::oldest_supporting(&with_min_updater_version), DefinitionVersion::Version2);
+    }
+
+    #[test]
+    fn uses_version3_for_chunked_files() {
+        let chunked = FileDefinition {
+            c_algo: CHUNKED_ALGO.to_owned(),
+            c_sha256: None,
+            mode: Some(0o644),
+            chunks: vec![ChunkDefinition { u_len: 100, u_sha256: "u".to_owned(), c_algo: Codec::Zstd.name().to_owned(), c_len: 50, c_sha256: "c".to_owned() }],
+            ..file()
+        };
+        assert_eq!(DefinitionVersion::oldest_supporting(&version(vec![file(), chunked])), DefinitionVersion::Version3);
+    }
+}
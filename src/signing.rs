@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use snafu::{whatever, ResultExt, Whatever};
+
+/// Suffix of the file (next to the version definition) that holds the signature of a version definition.
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// Kind of signed object of version definitions, see [`signed_document`].
+pub const VERSION_KIND: &str = "version";
+
+/// Kind of signed object of channels, see [`signed_document`].
+pub const CHANNEL_KIND: &str = "channel";
+
+/// Path (relative to the storage base path) of the signature of a version definition.
+pub fn signature_path(storage_base_path: &str, version_name: &str) -> PathBuf {
+    Path::new(storage_base_path).join("versions").join(format!("{version_name}{SIGNATURE_SUFFIX}"))
+}
+
+/// Builds the document a signature is made over: the kind and name of the signed object followed by its contents. As the
+/// name is signed too, a signed object can't be passed off as another one, e.g. by copying an old version definition and
+/// its signature to the name of the current version.
+pub fn signed_document(kind: &str, name: &str, contents: &[u8]) -> Vec<u8> {
+    let mut document = Vec::with_capacity(kind.len() + name.len() + contents.len() + 2);
+    document.extend_from_slice(kind.as_bytes());
+    document.push(0);
+    document.extend_from_slice(name.as_bytes());
+    document.push(0);
+    document.extend_from_slice(contents);
+    document
+}
+
+/// Parses a secret key, given as the Base64 encoding of its 32 bytes.
+pub fn parse_signing_key(text: &str) -> Result<SigningKey, Whatever> {
+    let bytes = BASE64_STANDARD.decode(text.trim()).with_whatever_context(|_| "Could not decode signing key as Base64")?;
+    match <[u8; 32]>::try_from(bytes.as_slice()) {
+        Ok(bytes) => Ok(SigningKey::from_bytes(&bytes)),
+        Err(_) => whatever!("Signing key must be 32 bytes, but is {} bytes", bytes.len()),
+    }
+}
+
+/// Parses a public key, given as the Base64 encoding of its 32 bytes.
+pub fn parse_public_key(text: &str) -> Result<VerifyingKey, Whatever> {
+    let bytes = BASE64_STANDARD.decode(text.trim()).with_whatever_context(|_| format!("Could not decode public key {text} as Base64"))?;
+    match <[u8; 32]>::try_from(bytes.as_slice()) {
+        Ok(bytes) => VerifyingKey::from_bytes(&bytes).with_whatever_context(|_| format!("Public key {text} is not a valid Ed25519 key")),
+        Err(_) => whatever!("Public key {text} must be 32 bytes, but is {} bytes", bytes.len()),
+    }
+}
+
+/// Encodes a public key the way [`parse_public_key`] expects it.
+pub fn encode_public_key(key: &VerifyingKey) -> String {
+    BASE64_STANDARD.encode(key.as_bytes())
+}
+
+/// Signs a document, returning the Base64 encoded signature.
+pub fn sign(key: &SigningKey, document: &[u8]) -> String {
+    BASE64_STANDARD.encode(key.sign(document).to_bytes())
+}
+
+/// Checks whether a Base64 encoded signature of a document was made by one of the trusted keys.
+pub fn verify(trusted_keys: &[VerifyingKey], document: &[u8], signature: &str) -> bool {
+    let Ok(bytes) = BASE64_STANDARD.decode(signature.trim()) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&bytes) else {
+        return false;
+    };
+
+    trusted_keys.iter().any(|key| key.verify_strict(document, &signature).is_ok())
+}
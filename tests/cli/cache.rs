@@ -0,0 +1,31 @@
+use std::{fs, path::Path};
+
+use crate::{random_data, Env};
+
+#[test]
+fn resumes_partial_downloads_from_cache() {
+    let env = Env::new();
+    let out = env.path("out");
+    let data = random_data(1, 200_000);
+    env.write_input("1.0.0", "data.bin", &data);
+    let public_key = env.create_with("1.0.0", "signing.key", &["--compression", "none"]);
+
+    // The start of the file is only intact in the partial download, so switching only succeeds by resuming it.
+    let u_sha256 = sha256::digest(&data);
+    fs::write(env.path(&format!("cache/{u_sha256}.none.part")), &data[..100_000]).unwrap();
+    let blob_path = env.path(&format!("storage/files/{u_sha256}"));
+    let mut corrupt_blob = data.clone();
+    corrupt_blob[..100_000].fill(0);
+    fs::write(&blob_path, &corrupt_blob).unwrap();
+
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+    assert_eq!(fs::read(Path::new(&out).join("data.bin")).unwrap(), data);
+    assert_eq!(fs::read(env.path(&format!("cache/{u_sha256}.none"))).unwrap(), data);
+    assert!(!Path::new(&env.path(&format!("cache/{u_sha256}.none.part"))).exists());
+
+    // Switching again uses the cached file instead of the storage.
+    fs::remove_file(&blob_path).unwrap();
+    fs::remove_file(Path::new(&out).join("data.bin")).unwrap();
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out]);
+    assert_eq!(fs::read(Path::new(&out).join("data.bin")).unwrap(), data);
+}
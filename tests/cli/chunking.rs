@@ -0,0 +1,36 @@
+use std::fs;
+
+use crate::{random_data, read_definition, Env};
+
+#[test]
+fn switches_reusing_unchanged_chunks() {
+    let env = Env::new();
+    let out = env.path("out");
+    let old_data = random_data(1, 10 * 1024 * 1024);
+    let mut new_data = old_data.clone();
+    new_data.splice(5_000_000..5_000_000, random_data(2, 1000));
+    env.write_input("1.0.0", "data.bin", &old_data);
+    env.create_with("1.0.0", "signing.key", &["--chunked"]);
+    env.write_input("1.1.0", "data.bin", &new_data);
+    let public_key = env.create_with("1.1.0", "signing.key", &["--chunked"]);
+
+    let definition = read_definition(&env, "1.1.0");
+    assert_eq!(definition["version"].as_u64(), Some(3));
+    let chunk_hashes = |name: &str| -> Vec<String> {
+        read_definition(&env, name)["files"][0]["chunks"].as_sequence().unwrap().iter().map(|c| c["uSha256"].as_str().unwrap().to_owned()).collect()
+    };
+    let old_chunks = chunk_hashes("1.0.0");
+    let new_chunks = chunk_hashes("1.1.0");
+    assert!(new_chunks.len() > 1);
+    assert!(new_chunks.iter().filter(|c| !old_chunks.contains(c)).count() <= 2);
+
+    // Without the unchanged chunks in the storage, switching only succeeds by reusing them from the old file.
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+    assert_eq!(fs::read(format!("{out}/data.bin")).unwrap(), old_data);
+    for chunk in new_chunks.iter().filter(|c| old_chunks.contains(c)) {
+        fs::remove_file(env.path(&format!("storage/chunks/{chunk}"))).unwrap();
+    }
+    env.run_ok(&["switch", "1.1.0", "--output-dir", &out]);
+    assert_eq!(fs::read(format!("{out}/data.bin")).unwrap(), new_data);
+    env.run_ok(&["verify", "--output-dir", &out]);
+}
@@ -0,0 +1,22 @@
+use crate::{random_data, read, read_definition, Env};
+
+#[test]
+fn switches_to_files_of_every_compression_algorithm() {
+    for (compression, expected_algos) in [("zstd", ["zstd", "zstd", "zstd"]), ("none", ["none", "none", "none"]), ("smallest", ["none", "none", "brotli"])] {
+        let env = Env::new();
+        let out = env.path("out");
+        env.write_input("1.0.0", "empty.txt", "");
+        env.write_input("1.0.0", "random.bin", random_data(1, 100_000));
+        env.write_input("1.0.0", "text.txt", "Some text that compresses well. ".repeat(1000));
+        let public_key = env.create_with("1.0.0", "signing.key", &["--compression", compression]);
+
+        let definition = read_definition(&env, "1.0.0");
+        let algos: Vec<&str> = definition["files"].as_sequence().unwrap().iter().map(|f| f["cAlgo"].as_str().unwrap()).collect();
+        assert_eq!(algos, expected_algos, "{compression}");
+
+        env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+        assert_eq!(read(&out, "empty.txt").as_deref(), Some(""));
+        assert_eq!(read(&out, "text.txt"), Some("Some text that compresses well. ".repeat(1000)));
+        env.run_ok(&["verify", "--output-dir", &out]);
+    }
+}
@@ -0,0 +1,29 @@
+use std::{fs, path::Path};
+
+use crate::{read, Env};
+
+#[test]
+fn create_switch_update_and_verify() {
+    let env = Env::new();
+    let out = env.path("out");
+    let public_key = env.create("1.0.0", &[("a.txt", "first"), ("sub/b.txt", "only in 1.0.0")], "signing.key");
+    env.create("1.1.0", &[("a.txt", "second"), ("c.txt", "only in 1.1.0")], "signing.key");
+
+    env.run_ok(&["promote", "1.0.0", "stable", "--signing-key", &env.path("signing.key")]);
+    env.run_ok(&["switch", "stable", "--output-dir", &out, "--trusted-key", &public_key]);
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("first"));
+    assert_eq!(read(&out, "sub/b.txt").as_deref(), Some("only in 1.0.0"));
+    assert!(Path::new(&out).join("empty").is_dir());
+    env.run_ok(&["verify", "--output-dir", &out]);
+
+    // The folder remembers the channel and trusted keys, so updating only needs the folder.
+    env.run_ok(&["promote", "1.1.0", "stable", "--signing-key", &env.path("signing.key")]);
+    env.run_ok(&["update", "--output-dir", &out]);
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("second"));
+    assert_eq!(read(&out, "c.txt").as_deref(), Some("only in 1.1.0"));
+    assert!(!Path::new(&out).join("sub").exists());
+    env.run_ok(&["verify", "--output-dir", &out]);
+
+    fs::write(Path::new(&out).join("a.txt"), "modified").unwrap();
+    assert!(!env.run(&["verify", "--output-dir", &out]).status.success());
+}
@@ -0,0 +1,29 @@
+use std::fs;
+
+use crate::{random_data, read_definition, Env};
+
+#[test]
+fn switches_using_patches() {
+    let env = Env::new();
+    let out = env.path("out");
+    let old_data = random_data(1, 300_000);
+    let mut new_data = old_data.clone();
+    new_data[150_000..150_100].fill(0);
+    env.write_input("1.0.0", "data.bin", &old_data);
+    env.create_with("1.0.0", "signing.key", &[]);
+    env.write_input("1.1.0", "data.bin", &new_data);
+    let public_key = env.create_with("1.1.0", "signing.key", &["--delta-from", "1.0.0"]);
+
+    let definition = read_definition(&env, "1.1.0");
+    let patches = definition["files"][0]["patches"].as_sequence().unwrap();
+    assert_eq!(patches.len(), 1);
+    assert!(patches[0]["pLen"].as_u64().unwrap() < 10_000);
+    assert_eq!(definition["version"].as_u64(), Some(2));
+
+    // Without the whole file in the storage, switching only succeeds by patching the old file.
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+    fs::remove_file(env.path(&format!("storage/files/{}", sha256::digest(&new_data)))).unwrap();
+    let output = env.run_ok(&["switch", "1.1.0", "--output-dir", &out]);
+    assert!(output.contains("(1 patched"), "{output}");
+    assert_eq!(fs::read(format!("{out}/data.bin")).unwrap(), new_data);
+}
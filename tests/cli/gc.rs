@@ -0,0 +1,67 @@
+use std::{fs, path::Path, time::{Duration, SystemTime}};
+
+use crate::Env;
+
+/// Makes a file in the storage look like it was uploaded two days ago.
+fn age(env: &Env, r_path: &str) {
+    let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+    filetime::set_file_mtime(env.path(&format!("storage/{r_path}")), filetime::FileTime::from_system_time(two_days_ago)).unwrap();
+}
+
+fn exists(env: &Env, r_path: &str) -> bool {
+    Path::new(&env.path(&format!("storage/{r_path}"))).exists()
+}
+
+#[test]
+fn removes_unreferenced_files_after_grace_period() {
+    let env = Env::new();
+    env.create("1.0.0", &[("a.txt", "shared"), ("b.txt", "only in 1.0.0")], "signing.key");
+    env.create("1.1.0", &[("a.txt", "shared"), ("c.txt", "only in 1.1.0")], "signing.key");
+    let shared = format!("files/{}", sha256::digest("shared"));
+    let only_old = format!("files/{}", sha256::digest("only in 1.0.0"));
+    let only_new = format!("files/{}", sha256::digest("only in 1.1.0"));
+
+    // Files stay as long as a version references them.
+    fs::remove_file(env.path("storage/versions/1.0.0")).unwrap();
+    for r_path in [&shared, &only_old, &"versions/1.0.0.sig".to_owned()] {
+        age(&env, r_path);
+    }
+    let output = env.run_ok(&["gc", "--dry-run"]);
+    assert!(output.contains("2 files"), "{output}");
+    assert!(exists(&env, &only_old));
+
+    env.run_ok(&["gc"]);
+    assert!(!exists(&env, &only_old));
+    assert!(!exists(&env, "versions/1.0.0.sig"));
+    for r_path in [&shared, &only_new, &"versions/1.1.0".to_owned(), &"versions/1.1.0.sig".to_owned()] {
+        assert!(exists(&env, r_path), "{r_path} was removed");
+    }
+
+    // Unreferenced files younger than the grace period are kept, e.g. those of a version that is still being created.
+    fs::remove_file(env.path("storage/versions/1.1.0")).unwrap();
+    let output = env.run_ok(&["gc"]);
+    assert!(output.contains("Kept 2 unreferenced files"), "{output}");
+    assert!(!exists(&env, &shared));
+    assert!(exists(&env, &only_new));
+    env.run_ok(&["gc", "--grace-period", "0s"]);
+    assert!(!exists(&env, &only_new));
+}
+
+#[test]
+fn create_uploads_unreferenced_files_again() {
+    let env = Env::new();
+    env.create("1.0.0", &[("a.txt", "first")], "signing.key");
+    let blob = format!("files/{}", sha256::digest("first"));
+    fs::remove_file(env.path("storage/versions/1.0.0")).unwrap();
+    age(&env, &blob);
+
+    // The old unreferenced file could be removed by a gc running at the same time, so it is not reused.
+    let output = env.run_ok(&["create", "1.1.0", "--input-dir", &env.path("in/1.0.0"), "--signing-key", &env.path("signing.key")]);
+    assert!(output.contains("0 already existing and 1 uploaded"), "{output}");
+    env.run_ok(&["gc", "--grace-period", "0s"]);
+    assert!(exists(&env, &blob));
+
+    // Referenced files are reused.
+    let output = env.run_ok(&["create", "1.2.0", "--input-dir", &env.path("in/1.0.0"), "--signing-key", &env.path("signing.key")]);
+    assert!(output.contains("1 already existing and 0 uploaded"), "{output}");
+}
@@ -0,0 +1,74 @@
+use std::{fs, io::{BufRead, BufReader, Write}, net::TcpListener, path::{Path, PathBuf}, thread};
+
+use crate::{random_data, read, Env};
+
+/// Starts a static web server for a directory that, like many CDNs, sends files chunked without a `Content-Length` and
+/// ignores ranges. If `interrupt_first_download` is set, the connection is closed halfway through the first file that is
+/// downloaded. Returns the URL of the root of the directory.
+fn serve(root: PathBuf, mut interrupt_first_download: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+            let response = match fs::read(root.join(path.trim_start_matches('/'))) {
+                Ok(contents) if method == "HEAD" => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", contents.len()).into_bytes(),
+                Ok(contents) if interrupt_first_download && path.starts_with("/files/") => {
+                    interrupt_first_download = false;
+                    let mut response = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n", contents.len()).into_bytes();
+                    response.extend_from_slice(&contents[..contents.len() / 2]);
+                    response
+                },
+                Ok(contents) => {
+                    let mut response = format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n", contents.len()).into_bytes();
+                    response.extend_from_slice(&contents);
+                    response.extend_from_slice(b"\r\n0\r\n\r\n");
+                    response
+                },
+                Err(_) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+            };
+            stream.write_all(&response).unwrap();
+        }
+    });
+
+    format!("static+http://{address}/")
+}
+
+#[test]
+fn switches_from_static_web_server() {
+    let env = Env::new();
+    let out = env.path("out");
+    let public_key = env.create("1.0.0", &[("a.txt", "first"), ("sub/b.txt", "only in 1.0.0")], "signing.key");
+    env.run_ok(&["promote", "1.0.0", "stable", "--signing-key", &env.path("signing.key")]);
+    let url = serve(PathBuf::from(env.path("storage")), false);
+
+    env.run_ok(&["switch", "stable", "--output-dir", &out, "--trusted-key", &public_key, "--s3-url", &url]);
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("first"));
+    assert_eq!(read(&out, "sub/b.txt").as_deref(), Some("only in 1.0.0"));
+    env.run_ok(&["verify", "--output-dir", &out]);
+    assert!(Path::new(&out).join("empty").is_dir());
+}
+
+#[test]
+fn resumes_interrupted_downloads() {
+    let env = Env::new();
+    let out = env.path("out");
+    let data = random_data(1, 300_000);
+    env.write_input("1.0.0", "data.bin", &data);
+    let public_key = env.create_with("1.0.0", "signing.key", &[]);
+    let url = serve(PathBuf::from(env.path("storage")), true);
+
+    let output = env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key, "--s3-url", &url]);
+    assert!(output.contains("Download of file data.bin is incomplete"), "{output}");
+    assert_eq!(fs::read(Path::new(&out).join("data.bin")).unwrap(), data);
+}
@@ -0,0 +1,28 @@
+use std::fs;
+
+use crate::{random_data, Env};
+
+#[test]
+fn downloads_corrupt_files_again() {
+    let env = Env::new();
+    let out = env.path("out");
+    let data = random_data(1, 100_000);
+    env.write_input("1.0.0", "data.bin", &data);
+    let public_key = env.create_with("1.0.0", "signing.key", &["--compression", "none"]);
+
+    // A corrupt cached file is downloaded from the storage again.
+    let u_sha256 = sha256::digest(&data);
+    fs::write(env.path(&format!("cache/{u_sha256}.none")), random_data(2, 100_000)).unwrap();
+    let output = env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+    assert!(output.contains("failed its integrity check"), "{output}");
+    assert_eq!(fs::read(format!("{out}/data.bin")).unwrap(), data);
+    assert_eq!(fs::read(env.path(&format!("cache/{u_sha256}.none"))).unwrap(), data);
+
+    // A corrupt file in the storage is given up on after a few attempts.
+    fs::remove_file(format!("{out}/data.bin")).unwrap();
+    fs::remove_file(env.path(&format!("cache/{u_sha256}.none"))).unwrap();
+    fs::write(env.path(&format!("storage/files/{u_sha256}")), random_data(2, 100_000)).unwrap();
+    let output = env.run(&["switch", "1.0.0", "--output-dir", &out]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("failed its integrity check 3 times"));
+}
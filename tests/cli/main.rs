@@ -0,0 +1,115 @@
+//! Tests running the h3xup binary against a storage in a local directory.
+
+mod cache;
+mod chunking;
+mod compression;
+mod create_switch;
+mod delta;
+mod gc;
+mod http;
+mod integrity;
+mod metadata;
+mod plan;
+mod signing;
+
+use std::{fs, path::Path, process::{Command, Output}};
+
+use tempfile::TempDir;
+use url::Url;
+
+// Base64 encoded Ed25519 secret keys of 32 zero and 32 one bytes.
+const SIGNING_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+const OTHER_SIGNING_KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+struct Env {
+    root: TempDir,
+    storage_url: String,
+}
+
+impl Env {
+    fn new() -> Env {
+        let root = TempDir::new().unwrap();
+        for dir in ["home", "cache", "storage", "out"] {
+            fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        fs::write(root.path().join("signing.key"), SIGNING_KEY).unwrap();
+        fs::write(root.path().join("other_signing.key"), OTHER_SIGNING_KEY).unwrap();
+        let storage_url = Url::from_directory_path(root.path().join("storage")).unwrap().to_string();
+
+        Env { root, storage_url }
+    }
+
+    fn path(&self, r_path: &str) -> String {
+        self.root.path().join(r_path).to_str().unwrap().to_owned()
+    }
+
+    /// Runs h3xup with the local storage, unless another one is passed using `--s3-url`.
+    fn run(&self, args: &[&str]) -> Output {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_h3xup"));
+        command.args(args);
+        if !args.contains(&"--s3-url") {
+            command.args(["--s3-url", &self.storage_url]);
+        }
+        let output = command
+            .env("HOME", self.path("home"))
+            .env("XDG_CONFIG_HOME", self.path("home/.config"))
+            .env("UPDTR_CACHE_DIR", self.path("cache"))
+            .env_remove("UPDTR_S3_URL")
+            .env_remove("UPDTR_OUTPUT_DIR")
+            .env_remove("UPDTR_FILESTORE_PATH_PREFIX")
+            .env_remove("UPDTR_SIGNING_KEY")
+            .output()
+            .unwrap();
+        println!("$ h3xup {}\n{}{}", args.join(" "), String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+
+        output
+    }
+
+    fn run_ok(&self, args: &[&str]) -> String {
+        let output = self.run(args);
+        assert!(output.status.success(), "h3xup {} failed", args.join(" "));
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Writes a file to the input directory of a version.
+    fn write_input(&self, name: &str, r_path: &str, contents: impl AsRef<[u8]>) {
+        let path = Path::new(&self.path(&format!("in/{name}"))).join(r_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Creates a version of the given files and an empty directory, returns the public key it was signed with.
+    fn create(&self, name: &str, files: &[(&str, &str)], signing_key: &str) -> String {
+        for (r_path, contents) in files {
+            self.write_input(name, r_path, contents);
+        }
+
+        self.create_with(name, signing_key, &[])
+    }
+
+    /// Creates a version of the files written using [`Env::write_input`] and an empty directory, passing extra arguments.
+    /// Returns the public key it was signed with.
+    fn create_with(&self, name: &str, signing_key: &str, args: &[&str]) -> String {
+        let input_dir = self.path(&format!("in/{name}"));
+        fs::create_dir_all(Path::new(&input_dir).join("empty")).unwrap();
+
+        let output = self.run_ok(&[&["create", name, "--input-dir", &input_dir, "--signing-key", &self.path(signing_key)], args].concat());
+        output.lines().find_map(|line| line.trim().strip_prefix("Signed with public key ")).unwrap().to_owned()
+    }
+}
+
+fn read(dir: &str, r_path: &str) -> Option<String> {
+    fs::read_to_string(Path::new(dir).join(r_path)).ok()
+}
+
+/// Deterministic data that doesn't compress, so patches and chunks are clearly smaller than the files.
+fn random_data(seed: u64, len: usize) -> Vec<u8> {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    (0..len).map(|_| rng.u8(..)).collect()
+}
+
+/// Reads a version definition from the storage.
+fn read_definition(env: &Env, name: &str) -> serde_yml::Value {
+    serde_yml::from_slice(&fs::read(env.path(&format!("storage/versions/{name}"))).unwrap()).unwrap()
+}
@@ -0,0 +1,55 @@
+#![cfg(unix)]
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path, time::{Duration, SystemTime}};
+
+use crate::{read_definition, Env};
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+fn mtime(path: &Path) -> SystemTime {
+    fs::metadata(path).unwrap().modified().unwrap()
+}
+
+#[test]
+fn restores_permissions_and_modification_times() {
+    let env = Env::new();
+    let out = env.path("out");
+    env.write_input("1.0.0", "run.sh", "#!/bin/sh\n");
+    env.write_input("1.0.0", "data.txt", "data");
+    let input_dir = Path::new(&env.path("in/1.0.0")).to_owned();
+    fs::set_permissions(input_dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(input_dir.join("data.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    let recorded_mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    filetime::set_file_mtime(input_dir.join("run.sh"), filetime::FileTime::from_system_time(recorded_mtime)).unwrap();
+    let public_key = env.create_with("1.0.0", "signing.key", &["--preserve-mtime"]);
+
+    let definition = read_definition(&env, "1.0.0");
+    let files = definition["files"].as_sequence().unwrap();
+    let run_sh = files.iter().find(|f| f["rPath"] == "run.sh").unwrap();
+    assert_eq!(run_sh["mode"].as_u64(), Some(0o755));
+    assert!(run_sh["mtime"].as_str().unwrap().starts_with("2020-09-13T12:26:40"));
+    assert_eq!(definition["version"].as_u64(), Some(2));
+
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &public_key]);
+    let out_dir = Path::new(&out);
+    assert_eq!(mode(&out_dir.join("run.sh")), 0o755);
+    assert_eq!(mode(&out_dir.join("data.txt")), 0o600);
+    assert_eq!(mtime(&out_dir.join("run.sh")), recorded_mtime);
+    env.run_ok(&["verify", "1.0.0", "--output-dir", &out]);
+
+    // Verify reports changed metadata, and switching fixes it without downloading the files again.
+    fs::set_permissions(out_dir.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+    filetime::set_file_mtime(out_dir.join("data.txt"), filetime::FileTime::from_system_time(recorded_mtime)).unwrap();
+    let output = env.run(&["verify", "1.0.0", "--output-dir", &out]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("2 with changed permissions or modification time"));
+
+    fs::remove_dir_all(env.path("storage/files")).unwrap();
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out]);
+    assert_eq!(mode(&out_dir.join("run.sh")), 0o755);
+    assert_eq!(mtime(&out_dir.join("run.sh")), recorded_mtime);
+    assert_ne!(mtime(&out_dir.join("data.txt")), recorded_mtime);
+    env.run_ok(&["verify", "1.0.0", "--output-dir", &out]);
+}
@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use walkdir::WalkDir;
+
+use crate::{random_data, Env};
+
+/// Reads all files of a folder, including the local state.
+fn snapshot(dir: &str) -> BTreeMap<String, Vec<u8>> {
+    WalkDir::new(dir).into_iter().map(Result::unwrap).filter(|e| e.file_type().is_file())
+        .map(|e| (e.path().strip_prefix(dir).unwrap().to_str().unwrap().to_owned(), fs::read(e.path()).unwrap()))
+        .collect()
+}
+
+#[test]
+fn dry_run_reports_changes_without_making_them() {
+    let env = Env::new();
+    let out = env.path("out");
+    let old_data = random_data(1, 300_000);
+    let mut new_data = old_data.clone();
+    new_data[150_000..150_100].fill(0);
+    env.write_input("1.0.0", "data.bin", &old_data);
+    let public_key = env.create("1.0.0", &[("a.txt", "first"), ("b.txt", "only in 1.0.0")], "signing.key");
+    env.write_input("1.1.0", "data.bin", &new_data);
+    for (r_path, contents) in [("a.txt", "second"), ("c.txt", "only in 1.1.0")] {
+        env.write_input("1.1.0", r_path, contents);
+    }
+    env.create_with("1.1.0", "signing.key", &["--delta-from", "1.0.0"]);
+
+    env.run_ok(&["promote", "1.0.0", "stable", "--signing-key", &env.path("signing.key")]);
+    env.run_ok(&["switch", "stable", "--output-dir", &out, "--trusted-key", &public_key]);
+    let before = snapshot(&out);
+
+    let output = env.run_ok(&["switch", "1.1.0", "--output-dir", &out, "--dry-run", "--format", "json"]);
+    let plan: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let paths = |key: &str| -> Vec<String> {
+        plan[key].as_array().unwrap().iter().map(|f| f.get("path").unwrap_or(f).as_str().unwrap().to_owned()).collect()
+    };
+    assert_eq!(paths("added"), ["c.txt"]);
+    assert_eq!(paths("replaced"), ["a.txt", "data.bin"]);
+    assert_eq!(paths("removed"), ["b.txt"]);
+    assert_eq!(plan["unchangedCount"], 0);
+    assert_eq!(plan["writeLen"], 6 + 13 + new_data.len() as u64);
+    let patched_file = &plan["replaced"][1];
+    assert_eq!(patched_file["patched"], true);
+    assert!(patched_file["downloadLen"].as_u64().unwrap() < 10_000);
+    assert_eq!(snapshot(&out), before);
+
+    // Updating follows the channel the folder was switched to.
+    env.run_ok(&["promote", "1.1.0", "stable", "--signing-key", &env.path("signing.key")]);
+    let output = env.run_ok(&["update", "--output-dir", &out, "--dry-run"]);
+    assert!(output.contains("Dry run, 1 added, 2 replaced, 1 removed, 0 fixed and 0 unchanged files"), "{output}");
+    assert_eq!(snapshot(&out), before);
+
+    env.run_ok(&["update", "--output-dir", &out]);
+    assert_eq!(fs::read(Path::new(&out).join("data.bin")).unwrap(), new_data);
+    assert!(!Path::new(&out).join("b.txt").exists());
+}
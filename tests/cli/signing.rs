@@ -0,0 +1,51 @@
+use std::{fs, path::Path};
+
+use crate::{read, Env};
+
+#[test]
+fn switch_rejects_untrusted_versions() {
+    let env = Env::new();
+    let out = env.path("out");
+    let public_key = env.create("1.0.0", &[("a.txt", "first")], "signing.key");
+    let other_public_key = env.create("other", &[("a.txt", "other")], "other_signing.key");
+
+    assert!(!env.run(&["switch", "1.0.0", "--output-dir", &out, "--trusted-key", &other_public_key]).status.success());
+    assert_eq!(read(&out, "a.txt"), None);
+
+    // A version copied to another name keeps its signature, which then no longer matches.
+    let definitions_dir = env.path("storage/versions");
+    fs::copy(Path::new(&definitions_dir).join("1.0.0"), Path::new(&definitions_dir).join("1.1.0")).unwrap();
+    fs::copy(Path::new(&definitions_dir).join("1.0.0.sig"), Path::new(&definitions_dir).join("1.1.0.sig")).unwrap();
+    assert!(!env.run(&["switch", "1.1.0", "--output-dir", &out, "--trusted-key", &public_key]).status.success());
+    assert_eq!(read(&out, "a.txt"), None);
+}
+
+#[test]
+fn update_rejects_replayed_channels() {
+    let env = Env::new();
+    let out = env.path("out");
+    let public_key = env.create("1.0.0", &[("a.txt", "first")], "signing.key");
+    env.create("1.1.0", &[("a.txt", "second")], "signing.key");
+    env.run_ok(&["promote", "1.0.0", "stable", "--signing-key", &env.path("signing.key")]);
+    env.run_ok(&["switch", "stable", "--output-dir", &out, "--trusted-key", &public_key]);
+
+    let channels_dir = env.path("storage/channels");
+    let old_channel = fs::read(Path::new(&channels_dir).join("stable")).unwrap();
+    let old_signature = fs::read(Path::new(&channels_dir).join("stable.sig")).unwrap();
+    env.run_ok(&["promote", "1.1.0", "stable", "--signing-key", &env.path("signing.key")]);
+    env.run_ok(&["update", "--output-dir", &out]);
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("second"));
+
+    // The old channel is still validly signed, but older than the one the folder has seen.
+    fs::write(Path::new(&channels_dir).join("stable"), old_channel).unwrap();
+    fs::write(Path::new(&channels_dir).join("stable.sig"), old_signature).unwrap();
+    let output = env.run(&["update", "--output-dir", &out]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("older than the one this folder was switched with"));
+    assert!(!env.run(&["update", "--output-dir", &out, "--dry-run"]).status.success());
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("second"));
+
+    // Versions can still be switched to by name.
+    env.run_ok(&["switch", "1.0.0", "--output-dir", &out]);
+    assert_eq!(read(&out, "a.txt").as_deref(), Some("first"));
+}